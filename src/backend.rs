@@ -0,0 +1,246 @@
+use bazuka::zk::ZkScalar;
+use bellman::{Circuit, SynthesisError};
+use std::io;
+use zeekit::BellmanFr;
+
+// A proving system, for the R1CS circuits in `circuits`. Groth16 needs a
+// circuit-specific setup, backends with a universal setup may ignore the
+// circuit type in `setup`.
+pub trait ProvingBackend {
+    type Params;
+    type VerifyingKey: Clone;
+    type Proof: Clone;
+
+    fn setup<C: Circuit<BellmanFr> + Default>() -> Result<Self::Params, SynthesisError>;
+    fn read_params<R: io::Read>(reader: R) -> io::Result<Self::Params>;
+    fn write_params<W: io::Write>(params: &Self::Params, writer: W) -> io::Result<()>;
+    fn verifying_key(params: &Self::Params) -> Self::VerifyingKey;
+
+    fn prove<C: Circuit<BellmanFr>>(
+        params: &Self::Params,
+        circuit: C,
+    ) -> Result<Self::Proof, SynthesisError>;
+    fn verify(
+        vk: &Self::VerifyingKey,
+        state: ZkScalar,
+        aux_data: ZkScalar,
+        next_state: ZkScalar,
+        proof: &Self::Proof,
+    ) -> bool;
+}
+
+#[cfg(not(any(feature = "groth16", feature = "mock")))]
+compile_error!("At least one proving backend (groth16 or mock) must be enabled!");
+
+// The backend of a `Bank` when none is given, Groth16 unless it's disabled
+#[cfg(feature = "groth16")]
+pub type DefaultBackend = Groth16Backend;
+#[cfg(all(feature = "mock", not(feature = "groth16")))]
+pub type DefaultBackend = MockBackend;
+
+#[cfg(feature = "groth16")]
+pub use self::groth16_backend::*;
+
+#[cfg(feature = "groth16")]
+mod groth16_backend {
+    use super::*;
+    use bellman::groth16;
+    use bls12_381::Bls12;
+    use rand::rngs::OsRng;
+
+    pub fn groth16_vk(
+        vk: &groth16::VerifyingKey<Bls12>,
+    ) -> bazuka::zk::groth16::Groth16VerifyingKey {
+        unsafe {
+            std::mem::transmute::<
+                groth16::VerifyingKey<Bls12>,
+                bazuka::zk::groth16::Groth16VerifyingKey,
+            >(vk.clone())
+        }
+    }
+
+    pub struct Groth16Backend;
+
+    impl ProvingBackend for Groth16Backend {
+        type Params = groth16::Parameters<Bls12>;
+        type VerifyingKey = bazuka::zk::groth16::Groth16VerifyingKey;
+        type Proof = bazuka::zk::groth16::Groth16Proof;
+
+        fn setup<C: Circuit<BellmanFr> + Default>() -> Result<Self::Params, SynthesisError> {
+            groth16::generate_random_parameters::<Bls12, _, _>(C::default(), &mut OsRng)
+        }
+        fn read_params<R: io::Read>(reader: R) -> io::Result<Self::Params> {
+            groth16::Parameters::<Bls12>::read(reader, false /* false for better performance*/)
+        }
+        fn write_params<W: io::Write>(params: &Self::Params, writer: W) -> io::Result<()> {
+            params.write(writer)
+        }
+        fn verifying_key(params: &Self::Params) -> Self::VerifyingKey {
+            groth16_vk(&params.vk)
+        }
+
+        fn prove<C: Circuit<BellmanFr>>(
+            params: &Self::Params,
+            circuit: C,
+        ) -> Result<Self::Proof, SynthesisError> {
+            Ok(unsafe {
+                std::mem::transmute::<groth16::Proof<Bls12>, bazuka::zk::groth16::Groth16Proof>(
+                    groth16::create_random_proof(circuit, params, &mut OsRng)?,
+                )
+            })
+        }
+        fn verify(
+            vk: &Self::VerifyingKey,
+            state: ZkScalar,
+            aux_data: ZkScalar,
+            next_state: ZkScalar,
+            proof: &Self::Proof,
+        ) -> bool {
+            bazuka::zk::groth16::groth16_verify(vk, state, aux_data, next_state, proof)
+        }
+    }
+}
+
+#[cfg(feature = "mock")]
+pub use self::mock_backend::*;
+
+// A backend without any setup, which only checks that the witness satisfies the
+// constraints. Its proofs are the public inputs themselves, so they are neither
+// zero-knowledge nor sound, and are only meant for testing and benchmarking the
+// witness generation. It is not a universal-setup backend: none is implemented
+// yet, Groth16 is the only backend that produces real proofs.
+#[cfg(feature = "mock")]
+mod mock_backend {
+    use super::*;
+    use bellman::{ConstraintSystem, Index, LinearCombination, Variable};
+    use ff::Field;
+
+    #[derive(Default)]
+    struct CheckingCs {
+        inputs: Vec<BellmanFr>,
+        aux: Vec<BellmanFr>,
+        namespace: Vec<String>,
+        unsatisfied: Option<String>,
+    }
+
+    impl CheckingCs {
+        fn eval(&self, lc: LinearCombination<BellmanFr>) -> BellmanFr {
+            let mut sum = BellmanFr::zero();
+            for (v, coeff) in lc.as_ref().iter() {
+                let mut val = match v.get_unchecked() {
+                    Index::Input(i) => self.inputs[i],
+                    Index::Aux(i) => self.aux[i],
+                };
+                val.mul_assign(coeff);
+                sum.add_assign(&val);
+            }
+            sum
+        }
+    }
+
+    impl ConstraintSystem<BellmanFr> for CheckingCs {
+        type Root = Self;
+
+        fn alloc<F, A, AR>(&mut self, _annotation: A, f: F) -> Result<Variable, SynthesisError>
+        where
+            F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+            A: FnOnce() -> AR,
+            AR: Into<String>,
+        {
+            self.aux.push(f()?);
+            Ok(Variable::new_unchecked(Index::Aux(self.aux.len() - 1)))
+        }
+
+        fn alloc_input<F, A, AR>(
+            &mut self,
+            _annotation: A,
+            f: F,
+        ) -> Result<Variable, SynthesisError>
+        where
+            F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+            A: FnOnce() -> AR,
+            AR: Into<String>,
+        {
+            self.inputs.push(f()?);
+            Ok(Variable::new_unchecked(Index::Input(self.inputs.len() - 1)))
+        }
+
+        fn enforce<A, AR, LA, LB, LC>(&mut self, annotation: A, a: LA, b: LB, c: LC)
+        where
+            A: FnOnce() -> AR,
+            AR: Into<String>,
+            LA: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+            LB: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+            LC: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        {
+            let mut ab = self.eval(a(LinearCombination::zero()));
+            ab.mul_assign(&self.eval(b(LinearCombination::zero())));
+            if ab != self.eval(c(LinearCombination::zero())) && self.unsatisfied.is_none() {
+                let mut path = self.namespace.clone();
+                path.push(annotation().into());
+                self.unsatisfied = Some(path.join("/"));
+            }
+        }
+
+        fn push_namespace<NR, N>(&mut self, name_fn: N)
+        where
+            NR: Into<String>,
+            N: FnOnce() -> NR,
+        {
+            self.namespace.push(name_fn().into());
+        }
+
+        fn pop_namespace(&mut self) {
+            self.namespace.pop();
+        }
+
+        fn get_root(&mut self) -> &mut Self::Root {
+            self
+        }
+    }
+
+    pub struct MockBackend;
+
+    impl ProvingBackend for MockBackend {
+        type Params = ();
+        type VerifyingKey = ();
+        type Proof = Vec<BellmanFr>;
+
+        fn setup<C: Circuit<BellmanFr> + Default>() -> Result<Self::Params, SynthesisError> {
+            Ok(())
+        }
+        fn read_params<R: io::Read>(_reader: R) -> io::Result<Self::Params> {
+            Ok(())
+        }
+        fn write_params<W: io::Write>(_params: &Self::Params, _writer: W) -> io::Result<()> {
+            Ok(())
+        }
+        fn verifying_key(_params: &Self::Params) -> Self::VerifyingKey {}
+
+        fn prove<C: Circuit<BellmanFr>>(
+            _params: &Self::Params,
+            circuit: C,
+        ) -> Result<Self::Proof, SynthesisError> {
+            let mut cs = CheckingCs {
+                inputs: vec![BellmanFr::one()],
+                ..Default::default()
+            };
+            circuit.synthesize(&mut cs)?;
+            if let Some(constraint) = cs.unsatisfied {
+                tracing::warn!(constraint, "Unsatisfied constraint");
+                return Err(SynthesisError::Unsatisfiable);
+            }
+            Ok(cs.inputs[1..].to_vec())
+        }
+        fn verify(
+            _vk: &Self::VerifyingKey,
+            state: ZkScalar,
+            aux_data: ZkScalar,
+            next_state: ZkScalar,
+            proof: &Self::Proof,
+        ) -> bool {
+            let inputs: [BellmanFr; 3] = [state.into(), aux_data.into(), next_state.into()];
+            proof[..] == inputs[..]
+        }
+    }
+}
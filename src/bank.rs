@@ -1,100 +1,24 @@
-use crate::config;
-use crate::{circuits, core};
+use crate::backend::{DefaultBackend, ProvingBackend};
+use crate::cache::MerkleCache;
+use crate::query::{AccountQuery, QueryError};
+use crate::registry::Registry;
+use crate::state::{
+    apply_delta, state_size_after, StateError, StateModelVersion, CHAIN_STATE_MODEL_VERSION,
+    STATE_MODEL_VERSION,
+};
+use crate::tokens;
+use crate::{circuits, core, witness};
 use bazuka::zk::ZkScalar;
 use bazuka::{
     config::blockchain::MPN_CONTRACT_ID,
     core::ZkHasher,
-    crypto::jubjub::{PointAffine, PublicKey},
+    crypto::jubjub::PublicKey,
     db::KvStore,
-    zk::{DepositWithdraw, KvStoreStateManager, ZeroTransaction, ZkDataLocator, ZkStateModel},
+    zk::{DepositWithdraw, KvStoreStateManager, ZeroTransaction, ZkDataLocator},
 };
-use bellman::groth16;
-use bellman::groth16::Parameters;
-use bls12_381::Bls12;
-use rand::rngs::OsRng;
-
-lazy_static! {
-    pub static ref STATE_MODEL: ZkStateModel = {
-        ZkStateModel::List {
-            log4_size: config::LOG4_TREE_SIZE,
-            item_type: Box::new(ZkStateModel::Struct {
-                field_types: vec![
-                    ZkStateModel::Scalar, // Nonce
-                    ZkStateModel::Scalar, // Pub-key X
-                    ZkStateModel::Scalar, // Pub-key Y
-                    ZkStateModel::Scalar, // Balance
-                ],
-            }),
-        }
-    };
-}
-
-pub fn get_account<K: KvStore>(db: &K, index: u32) -> core::Account {
-    let nonce: u64 = KvStoreStateManager::<ZkHasher>::get_data(
-        db,
-        *MPN_CONTRACT_ID,
-        &ZkDataLocator(vec![index, 0]),
-    )
-    .unwrap()
-    .try_into()
-    .unwrap();
-    let pub_x = KvStoreStateManager::<ZkHasher>::get_data(
-        db,
-        *MPN_CONTRACT_ID,
-        &ZkDataLocator(vec![index, 1]),
-    )
-    .unwrap();
-    let pub_y = KvStoreStateManager::<ZkHasher>::get_data(
-        db,
-        *MPN_CONTRACT_ID,
-        &ZkDataLocator(vec![index, 2]),
-    )
-    .unwrap();
-    let balance: u64 = KvStoreStateManager::<ZkHasher>::get_data(
-        db,
-        *MPN_CONTRACT_ID,
-        &ZkDataLocator(vec![index, 3]),
-    )
-    .unwrap()
-    .try_into()
-    .unwrap();
-    core::Account {
-        nonce,
-        balance,
-        address: PointAffine(pub_x, pub_y),
-    }
-}
-
-pub fn set_account<K: KvStore>(db: &mut K, index: u32, acc: core::Account) {
-    KvStoreStateManager::<ZkHasher>::set_data(
-        db,
-        *MPN_CONTRACT_ID,
-        ZkDataLocator(vec![index, 0]),
-        ZkScalar::from(acc.nonce),
-    )
-    .unwrap();
-    KvStoreStateManager::<ZkHasher>::set_data(
-        db,
-        *MPN_CONTRACT_ID,
-        ZkDataLocator(vec![index, 1]),
-        acc.address.0,
-    )
-    .unwrap();
-    KvStoreStateManager::<ZkHasher>::set_data(
-        db,
-        *MPN_CONTRACT_ID,
-        ZkDataLocator(vec![index, 2]),
-        acc.address.1,
-    )
-    .unwrap();
-    KvStoreStateManager::<ZkHasher>::set_data(
-        db,
-        *MPN_CONTRACT_ID,
-        ZkDataLocator(vec![index, 3]),
-        ZkScalar::from(acc.balance),
-    )
-    .unwrap();
-}
+use std::path::PathBuf;
+use tracing::{debug, warn};
+use zeekit::BellmanFr;
 
 #[derive(Clone, Debug)]
 pub enum BankError {
@@ -102,154 +26,485 @@ pub enum BankError {
     InvalidNonce,
     InvalidSignature,
     InvalidPublicKey,
+    InvalidToken,
+    PublicKeyAlreadyRegistered,
+    InvalidDelta,
+    CannotReadState,
     CannotProve,
 }
 
-pub struct Bank {
-    update_params: Parameters<Bls12>,
-    deposit_withdraw_params: Parameters<Bls12>,
+// Accounts with tokens the state model has no slot for are invalid, other
+// errors mean the state cannot be read.
+fn state_error(e: StateError) -> BankError {
+    match e {
+        StateError::TokensNotSupported(..) => BankError::InvalidToken,
+        e => {
+            warn!(error = %e, "Cannot read the state");
+            BankError::CannotReadState
+        }
+    }
 }
 
-pub fn extract_delta(ops: Vec<bazuka::db::WriteOp>) -> bazuka::zk::ZkDeltaPairs {
-    let mut pairs = bazuka::zk::ZkDeltaPairs([].into());
-    for op in ops {
-        match op {
-            bazuka::db::WriteOp::Put(k, v) => {
-                let mut it = k.0.split("_s_");
-                it.next();
-                if let Some(loc) = it.next() {
-                    pairs
-                        .0
-                        .insert(loc.parse().unwrap(), Some(v.try_into().unwrap()));
-                }
-            }
-            bazuka::db::WriteOp::Remove(k) => {
-                let mut it = k.0.split("_s_");
-                it.next();
-                if let Some(loc) = it.next() {
-                    pairs.0.insert(loc.parse().unwrap(), None);
-                }
-            }
+// Proofs are checked against the VKs of the parameters, unless the VKs that the
+// chain verifies with are given through `with_vks`.
+pub struct Bank<B: ProvingBackend = DefaultBackend> {
+    update_params: B::Params,
+    deposit_withdraw_params: B::Params,
+    mpn_params: B::Params,
+    update_vk: B::VerifyingKey,
+    deposit_withdraw_vk: B::VerifyingKey,
+    mpn_vk: B::VerifyingKey,
+    witness_dir: Option<PathBuf>,
+}
+
+// A batch with its witness built, ready to be proven
+#[derive(Clone)]
+pub struct PreparedBatch<C> {
+    pub state: ZkScalar,
+    pub aux_data: ZkScalar,
+    pub delta: bazuka::zk::ZkDeltaPairs,
+    pub next_state: bazuka::zk::ZkCompressedState,
+    pub circuit: C,
+    pub ops: Vec<bazuka::db::WriteOp>, // For predicting the state after the batch
+}
+
+// Applies transactions on an overlay of the state, with the checks done when
+// building a batch. The resulting transitions are the witness of the batch. The
+// state should have the model version of the circuit the batch is built for:
+// `CHAIN_STATE_MODEL_VERSION` for transfers and deposit/withdraws, and
+// `STATE_MODEL_VERSION` for token operations.
+pub struct StateOverlay<'a, K: KvStore> {
+    db: &'a K,
+    cache: MerkleCache,
+    registry: Registry,
+}
+
+impl<'a, K: KvStore> StateOverlay<'a, K> {
+    pub fn new(db: &'a K, version: StateModelVersion) -> Result<Self, BankError> {
+        let registry = Registry::for_state(db, version).map_err(|e| {
+            warn!(error = %e, "Cannot read the registry");
+            BankError::CannotReadState
+        })?;
+        Ok(Self {
+            db,
+            cache: MerkleCache::new(version),
+            registry,
+        })
+    }
+
+    pub fn apply_deposit_withdraw(
+        &mut self,
+        tx: &DepositWithdraw,
+    ) -> Result<circuits::DepositWithdrawTransition, BankError> {
+        let acc = self
+            .cache
+            .get_account(self.db, tx.index)
+            .map_err(state_error)?;
+        if acc.address != Default::default() && tx.pub_key.0.decompress() != acc.address {
+            Err(BankError::InvalidPublicKey)
+        } else if self.registry.is_taken(tx.index, &tx.pub_key.0.decompress()) {
+            Err(BankError::PublicKeyAlreadyRegistered)
+        } else if tx.amount < 0 && acc.balance as i64 + tx.amount < 0 {
+            Err(BankError::BalanceInsufficient)
+        } else {
+            let updated_acc = core::Account {
+                address: tx.pub_key.0.decompress(),
+                balance: (acc.balance as i64 + tx.amount) as u64,
+                nonce: acc.nonce,
+                tokens: acc.tokens.clone(),
+            };
+
+            let proof = self.cache.prove(self.db, tx.index).map_err(state_error)?;
+
+            self.registry.register(tx.index, &updated_acc.address);
+            self.cache
+                .set_account(self.db, tx.index, updated_acc)
+                .map_err(state_error)?;
+
+            Ok(circuits::DepositWithdrawTransition {
+                enabled: true,
+                tx: tx.clone(),
+                before: acc,
+                proof,
+            })
         }
     }
-    pairs
-}
 
-impl Bank {
-    pub fn balances<K: KvStore>(&self, db: &K) -> Vec<(u32, u64)> {
-        let state = KvStoreStateManager::<ZkHasher>::get_full_state(db, *MPN_CONTRACT_ID).unwrap();
-        let mut result = Vec::new();
-        for (loc, val) in state.data.0 {
-            if loc.0[1] == 3 {
-                result.push((loc.0[0], val.try_into().unwrap()));
-            }
+    pub fn apply_transfer(
+        &mut self,
+        tx: &ZeroTransaction,
+    ) -> Result<circuits::Transition, BankError> {
+        let src_before = self
+            .cache
+            .get_account(self.db, tx.src_index)
+            .map_err(state_error)?;
+        if tx.nonce != src_before.nonce {
+            Err(BankError::InvalidNonce)
+        } else if !tx.verify(PublicKey(src_before.address.compress())) {
+            Err(BankError::InvalidSignature)
+        } else if src_before.balance < tx.fee + tx.amount {
+            Err(BankError::BalanceInsufficient)
+        } else if self
+            .registry
+            .is_taken(tx.dst_index, &tx.dst_pub_key.0.decompress())
+        {
+            Err(BankError::PublicKeyAlreadyRegistered)
+        } else {
+            let src_proof = self
+                .cache
+                .prove(self.db, tx.src_index)
+                .map_err(state_error)?;
+            let src_after = core::Account {
+                address: src_before.address.clone(),
+                balance: src_before.balance - tx.fee - tx.amount,
+                nonce: src_before.nonce + 1,
+                tokens: src_before.tokens.clone(),
+            };
+            self.cache
+                .set_account(self.db, tx.src_index, src_after)
+                .map_err(state_error)?;
+
+            let dst_before = self
+                .cache
+                .get_account(self.db, tx.dst_index)
+                .map_err(state_error)?;
+            let dst_proof = self
+                .cache
+                .prove(self.db, tx.dst_index)
+                .map_err(state_error)?;
+
+            let dst_after = core::Account {
+                address: tx.dst_pub_key.0.decompress(),
+                balance: dst_before.balance + tx.amount,
+                nonce: dst_before.nonce,
+                tokens: dst_before.tokens.clone(),
+            };
+            self.registry.register(tx.dst_index, &dst_after.address);
+            self.cache
+                .set_account(self.db, tx.dst_index, dst_after)
+                .map_err(state_error)?;
+
+            Ok(circuits::Transition {
+                enabled: true,
+                tx: tx.clone(),
+                src_before,
+                src_proof,
+                dst_before,
+                dst_proof,
+            })
         }
-        result
     }
-    pub fn new(
-        update_params: Parameters<Bls12>,
-        deposit_withdraw_params: Parameters<Bls12>,
-    ) -> Self {
-        Self {
-            update_params,
-            deposit_withdraw_params,
+
+    pub fn apply_token_deposit_withdraw(
+        &mut self,
+        tx: &core::TokenDepositWithdraw,
+    ) -> Result<circuits::TokenDepositWithdrawTransition, BankError> {
+        let acc = self
+            .cache
+            .get_account(self.db, tx.index)
+            .map_err(state_error)?;
+        let token = acc
+            .tokens
+            .get(tx.token_index as usize)
+            .cloned()
+            .ok_or(BankError::InvalidToken)?;
+        if acc.address != Default::default() && tx.pub_key.0.decompress() != acc.address {
+            Err(BankError::InvalidPublicKey)
+        } else if self.registry.is_taken(tx.index, &tx.pub_key.0.decompress()) {
+            Err(BankError::PublicKeyAlreadyRegistered)
+        } else if token.amount != 0 && token.token_id != tx.token_id {
+            Err(BankError::InvalidToken)
+        } else if tx.amount < 0 && token.amount as i64 + tx.amount < 0 {
+            Err(BankError::BalanceInsufficient)
+        } else {
+            let proof = self.cache.prove(self.db, tx.index).map_err(state_error)?;
+            let token_proof = tokens::prove_token(&acc, tx.token_index);
+
+            let mut updated_acc = acc.clone();
+            updated_acc.address = tx.pub_key.0.decompress();
+            updated_acc.tokens[tx.token_index as usize] = core::Token {
+                token_id: tx.token_id,
+                amount: (token.amount as i64 + tx.amount) as u64,
+            };
+            self.registry.register(tx.index, &updated_acc.address);
+            self.cache
+                .set_account(self.db, tx.index, updated_acc)
+                .map_err(state_error)?;
+
+            Ok(circuits::TokenDepositWithdrawTransition {
+                enabled: true,
+                tx: tx.clone(),
+                before: acc,
+                proof,
+                token_proof,
+            })
         }
     }
 
-    pub fn deposit_withdraw<K: KvStore>(
-        &self,
-        db: &K,
-        txs: Vec<DepositWithdraw>,
-    ) -> Result<
-        (
-            bazuka::zk::ZkDeltaPairs,
-            bazuka::zk::ZkCompressedState,
-            bazuka::zk::groth16::Groth16Proof,
-        ),
-        BankError,
-    > {
-        let mut mirror = db.mirror();
+    pub fn apply_token_transfer(
+        &mut self,
+        tx: &core::TokenTransfer,
+    ) -> Result<circuits::TokenTransition, BankError> {
+        let src_before = self
+            .cache
+            .get_account(self.db, tx.src_index)
+            .map_err(state_error)?;
+        let src_token = src_before
+            .tokens
+            .get(tx.src_token_index as usize)
+            .cloned()
+            .ok_or(BankError::InvalidToken)?;
+        if tx.nonce != src_before.nonce {
+            Err(BankError::InvalidNonce)
+        } else if !tx.verify(PublicKey(src_before.address.compress())) {
+            Err(BankError::InvalidSignature)
+        } else if src_token.token_id != tx.token_id {
+            Err(BankError::InvalidToken)
+        } else if src_token.amount < tx.fee + tx.amount {
+            Err(BankError::BalanceInsufficient)
+        } else if self
+            .registry
+            .is_taken(tx.dst_index, &tx.dst_pub_key.0.decompress())
+        {
+            Err(BankError::PublicKeyAlreadyRegistered)
+        } else {
+            let mut src_after = src_before.clone();
+            src_after.nonce += 1;
+            src_after.tokens[tx.src_token_index as usize].amount -= tx.fee + tx.amount;
 
-        let mut transitions = Vec::new();
-        let state =
-            KvStoreStateManager::<ZkHasher>::get_data(db, *MPN_CONTRACT_ID, &ZkDataLocator(vec![]))
-                .unwrap();
-        for tx in txs.iter() {
-            let acc = get_account(&mirror, tx.index);
-            if acc.address != Default::default() && tx.pub_key.0.decompress() != acc.address {
-                return Err(BankError::InvalidPublicKey);
-            } else if tx.amount < 0 && acc.balance as i64 + tx.amount < 0 {
-                return Err(BankError::BalanceInsufficient);
+            // The destination is validated as it is after the source update,
+            // before anything is applied on the overlay.
+            let dst_before = if tx.dst_index == tx.src_index {
+                src_after.clone()
             } else {
-                let updated_acc = core::Account {
-                    address: tx.pub_key.0.decompress(),
-                    balance: (acc.balance as i64 + tx.amount) as u64,
-                    nonce: acc.nonce,
-                };
-
-                let proof = zeekit::merkle::Proof::<{ config::LOG4_TREE_SIZE }>(
-                    KvStoreStateManager::<ZkHasher>::prove(
-                        &mirror,
-                        *MPN_CONTRACT_ID,
-                        ZkDataLocator(vec![]),
-                        tx.index,
-                    )
-                    .unwrap(),
-                );
+                self.cache
+                    .get_account(self.db, tx.dst_index)
+                    .map_err(state_error)?
+            };
+            let dst_token = dst_before
+                .tokens
+                .get(tx.dst_token_index as usize)
+                .cloned()
+                .ok_or(BankError::InvalidToken)?;
+            if dst_token.amount != 0 && dst_token.token_id != tx.token_id {
+                return Err(BankError::InvalidToken);
+            }
 
-                set_account(&mut mirror, tx.index, updated_acc);
+            let src_proof = self
+                .cache
+                .prove(self.db, tx.src_index)
+                .map_err(state_error)?;
+            let src_token_proof = tokens::prove_token(&src_before, tx.src_token_index);
+            self.cache
+                .set_account(self.db, tx.src_index, src_after)
+                .map_err(state_error)?;
 
-                transitions.push(circuits::DepositWithdrawTransition {
-                    enabled: true,
-                    tx: tx.clone(),
-                    before: acc,
-                    proof,
-                });
-            }
+            let dst_proof = self
+                .cache
+                .prove(self.db, tx.dst_index)
+                .map_err(state_error)?;
+            let dst_token_proof = tokens::prove_token(&dst_before, tx.dst_token_index);
+
+            let mut dst_after = dst_before.clone();
+            dst_after.address = tx.dst_pub_key.0.decompress();
+            dst_after.tokens[tx.dst_token_index as usize] = core::Token {
+                token_id: tx.token_id,
+                amount: dst_token.amount + tx.amount,
+            };
+            self.registry.register(tx.dst_index, &dst_after.address);
+            self.cache
+                .set_account(self.db, tx.dst_index, dst_after)
+                .map_err(state_error)?;
+
+            Ok(circuits::TokenTransition {
+                enabled: true,
+                tx: tx.clone(),
+                src_before,
+                src_proof,
+                src_token_proof,
+                dst_before,
+                dst_proof,
+                dst_token_proof,
+            })
         }
+    }
+
+    // Builds the batch of the applied transitions. Applying the delta of the
+    // overlay on the state should result in the root calculated by the cache.
+    fn into_batch<C, F: FnOnce(ZkScalar, ZkScalar, ZkScalar) -> C>(
+        self,
+        circuit: F,
+    ) -> Result<PreparedBatch<C>, BankError> {
+        let state = KvStoreStateManager::<ZkHasher>::get_data(
+            self.db,
+            *MPN_CONTRACT_ID,
+            &ZkDataLocator(vec![]),
+        )
+        .map_err(|e| state_error(e.into()))?;
+        let delta = self.cache.delta().map_err(state_error)?;
+        let mut mirror = self.db.mirror();
+        apply_delta(&mut mirror, self.cache.version(), &delta)
+            .map_err(|_| BankError::InvalidDelta)?;
         let next_state = KvStoreStateManager::<ZkHasher>::get_data(
             &mirror,
             *MPN_CONTRACT_ID,
             &ZkDataLocator(vec![]),
         )
-        .unwrap();
+        .map_err(|e| state_error(e.into()))?;
+        if self.cache.root().map(|r| r != next_state).unwrap_or(false) {
+            return Err(BankError::InvalidDelta);
+        }
+        let state_size = state_size_after(self.db, &delta).map_err(state_error)?;
         let aux_data = ZkScalar::from(0);
 
-        let circuit = circuits::DepositWithdrawCircuit {
-            filled: true,
+        let ops = mirror.to_ops();
+        self.registry.remember(next_state);
+        Ok(PreparedBatch {
             state,
             aux_data,
-            next_state,
-            transitions: Box::new(circuits::DepositWithdrawTransitionBatch::new(transitions)),
-        };
+            delta,
+            ops,
+            next_state: bazuka::zk::ZkCompressedState {
+                state_hash: next_state,
+                state_size,
+            },
+            circuit: circuit(state, aux_data, next_state),
+        })
+    }
+}
+
+impl<B: ProvingBackend> Bank<B> {
+    pub fn balances<K: KvStore>(&self, db: &K) -> Result<Vec<(u32, u64)>, QueryError> {
+        Ok(AccountQuery::new(db, CHAIN_STATE_MODEL_VERSION)
+            .list(0, usize::MAX, false)?
+            .into_iter()
+            .map(|(index, acc)| (index, acc.balance))
+            .collect())
+    }
+    pub fn new(
+        update_params: B::Params,
+        deposit_withdraw_params: B::Params,
+        mpn_params: B::Params,
+    ) -> Self {
+        Self {
+            update_vk: B::verifying_key(&update_params),
+            deposit_withdraw_vk: B::verifying_key(&deposit_withdraw_params),
+            mpn_vk: B::verifying_key(&mpn_params),
+            update_params,
+            deposit_withdraw_params,
+            mpn_params,
+            witness_dir: None,
+        }
+    }
 
+    // Verify the update and deposit/withdraw proofs with the on-chain VKs
+    pub fn with_vks(
+        mut self,
+        update_vk: B::VerifyingKey,
+        deposit_withdraw_vk: B::VerifyingKey,
+    ) -> Self {
+        self.update_vk = update_vk;
+        self.deposit_withdraw_vk = deposit_withdraw_vk;
+        self
+    }
+
+    // Store the witness of each proven batch as a file in `dir`
+    pub fn with_witness_dir(mut self, dir: PathBuf) -> Self {
+        self.witness_dir = Some(dir);
+        self
+    }
+
+    fn save_witness(&self, witness: witness::Witness) {
+        if let Some(dir) = &self.witness_dir {
+            let timestamp = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis();
+            let path = dir.join(format!("{}_{}.json", witness.circuit_name(), timestamp));
+            if let Err(e) = witness.save(&path) {
+                warn!(error = %e, path = %path.display(), "Unable to save witness");
+            }
+        }
+    }
+
+    fn prove<C: bellman::Circuit<BellmanFr>>(
+        params: &B::Params,
+        circuit: C,
+    ) -> Result<B::Proof, BankError> {
         let start = std::time::Instant::now();
-        let proof = unsafe {
-            std::mem::transmute::<bellman::groth16::Proof<Bls12>, bazuka::zk::groth16::Groth16Proof>(
-                groth16::create_random_proof(circuit, &self.deposit_withdraw_params, &mut OsRng)
-                    .unwrap(),
-            )
-        };
-        println!(
-            "Proving took: {}ms",
-            (std::time::Instant::now() - start).as_millis()
+        let proof = B::prove(params, circuit).map_err(|_| BankError::CannotProve)?;
+        debug!(
+            proving_ms = start.elapsed().as_millis() as u64,
+            "Proof generated"
         );
+        Ok(proof)
+    }
 
-        if bazuka::zk::groth16::groth16_verify(
-            &bazuka::config::blockchain::MPN_DEPOSIT_WITHDRAW_VK,
-            state,
-            aux_data,
-            next_state,
+    pub fn prepare_deposit_withdraw<K: KvStore>(
+        &self,
+        db: &K,
+        txs: Vec<DepositWithdraw>,
+    ) -> Result<PreparedBatch<circuits::DepositWithdrawCircuit>, BankError> {
+        let mut overlay = StateOverlay::new(db, CHAIN_STATE_MODEL_VERSION)?;
+        let mut transitions = Vec::new();
+        for tx in txs.iter() {
+            transitions.push(overlay.apply_deposit_withdraw(tx)?);
+        }
+        overlay.into_batch(
+            |state, aux_data, next_state| circuits::DepositWithdrawCircuit {
+                filled: true,
+                state,
+                aux_data,
+                next_state,
+                transitions: Box::new(circuits::DepositWithdrawTransitionBatch::new(transitions)),
+            },
+        )
+    }
+    pub fn verify_deposit_withdraw<C>(&self, batch: &PreparedBatch<C>, proof: &B::Proof) -> bool {
+        B::verify(
+            &self.deposit_withdraw_vk,
+            batch.state,
+            batch.aux_data,
+            batch.next_state.state_hash,
+            proof,
+        )
+    }
+    pub fn deposit_withdraw<K: KvStore>(
+        &self,
+        db: &K,
+        txs: Vec<DepositWithdraw>,
+    ) -> Result<
+        (
+            bazuka::zk::ZkDeltaPairs,
+            bazuka::zk::ZkCompressedState,
+            B::Proof,
+        ),
+        BankError,
+    > {
+        self.prove_deposit_withdraw(self.prepare_deposit_withdraw(db, txs)?)
+    }
+    pub fn prove_deposit_withdraw(
+        &self,
+        batch: PreparedBatch<circuits::DepositWithdrawCircuit>,
+    ) -> Result<
+        (
+            bazuka::zk::ZkDeltaPairs,
+            bazuka::zk::ZkCompressedState,
+            B::Proof,
+        ),
+        BankError,
+    > {
+        self.save_witness(witness::Witness::deposit_withdraw(&batch.circuit));
+        let proof = Self::prove(&self.deposit_withdraw_params, batch.circuit)?;
+
+        if B::verify(
+            &self.deposit_withdraw_vk,
+            batch.state,
+            batch.aux_data,
+            batch.next_state.state_hash,
             &proof,
         ) {
-            Ok((
-                extract_delta(mirror.to_ops()),
-                bazuka::zk::ZkCompressedState {
-                    state_hash: next_state,
-                    state_size: 0,
-                },
-                proof,
-            ))
+            Ok((batch.delta, batch.next_state, proof))
         } else {
             Err(BankError::CannotProve)
         }
@@ -257,6 +512,33 @@ impl Bank {
     pub fn root<K: KvStore>(&self, db: &K) -> bazuka::zk::ZkCompressedState {
         KvStoreStateManager::<ZkHasher>::root(db, *MPN_CONTRACT_ID).unwrap()
     }
+    pub fn prepare_change_state<K: KvStore>(
+        &self,
+        db: &K,
+        txs: Vec<ZeroTransaction>,
+    ) -> Result<PreparedBatch<circuits::UpdateCircuit>, BankError> {
+        let mut overlay = StateOverlay::new(db, CHAIN_STATE_MODEL_VERSION)?;
+        let mut transitions = Vec::new();
+        for tx in txs.iter() {
+            transitions.push(overlay.apply_transfer(tx)?);
+        }
+        overlay.into_batch(|state, aux_data, next_state| circuits::UpdateCircuit {
+            filled: true,
+            state,
+            aux_data,
+            next_state,
+            transitions: Box::new(circuits::TransitionBatch::new(transitions)),
+        })
+    }
+    pub fn verify_change_state<C>(&self, batch: &PreparedBatch<C>, proof: &B::Proof) -> bool {
+        B::verify(
+            &self.update_vk,
+            batch.state,
+            batch.aux_data,
+            batch.next_state.state_hash,
+            proof,
+        )
+    }
     pub fn change_state<K: KvStore>(
         &self,
         db: &K,
@@ -265,116 +547,172 @@ impl Bank {
         (
             bazuka::zk::ZkDeltaPairs,
             bazuka::zk::ZkCompressedState,
-            bazuka::zk::groth16::Groth16Proof,
+            B::Proof,
         ),
         BankError,
     > {
-        let mut transitions = Vec::new();
-
-        let state =
-            KvStoreStateManager::<ZkHasher>::get_data(db, *MPN_CONTRACT_ID, &ZkDataLocator(vec![]))
-                .unwrap();
+        self.prove_change_state(self.prepare_change_state(db, txs)?)
+    }
+    pub fn prove_change_state(
+        &self,
+        batch: PreparedBatch<circuits::UpdateCircuit>,
+    ) -> Result<
+        (
+            bazuka::zk::ZkDeltaPairs,
+            bazuka::zk::ZkCompressedState,
+            B::Proof,
+        ),
+        BankError,
+    > {
+        self.save_witness(witness::Witness::update(&batch.circuit));
+        let proof = Self::prove(&self.update_params, batch.circuit)?;
 
-        let mut mirror = db.mirror();
+        if B::verify(
+            &self.update_vk,
+            batch.state,
+            batch.aux_data,
+            batch.next_state.state_hash,
+            &proof,
+        ) {
+            Ok((batch.delta, batch.next_state, proof))
+        } else {
+            Err(BankError::CannotProve)
+        }
+    }
 
-        for tx in txs.iter() {
-            let src_before = get_account(&mirror, tx.src_index);
-            if tx.nonce != src_before.nonce {
-                return Err(BankError::InvalidNonce);
-            } else if !tx.verify(PublicKey(src_before.address.compress())) {
-                return Err(BankError::InvalidSignature);
-            } else if src_before.balance < tx.fee + tx.amount {
-                return Err(BankError::BalanceInsufficient);
-            } else {
-                let src_proof = zeekit::merkle::Proof::<{ config::LOG4_TREE_SIZE }>(
-                    KvStoreStateManager::<ZkHasher>::prove(
-                        &mirror,
-                        *MPN_CONTRACT_ID,
-                        ZkDataLocator(vec![]),
-                        tx.src_index,
-                    )
-                    .unwrap(),
-                );
-                let src_after = core::Account {
-                    address: src_before.address.clone(),
-                    balance: src_before.balance - tx.fee - tx.amount,
-                    nonce: src_before.nonce + 1,
-                };
-                set_account(&mut mirror, tx.src_index, src_after);
-
-                let dst_before = get_account(&mirror, tx.dst_index);
-                let dst_proof = zeekit::merkle::Proof::<{ config::LOG4_TREE_SIZE }>(
-                    KvStoreStateManager::<ZkHasher>::prove(
-                        &mirror,
-                        *MPN_CONTRACT_ID,
-                        ZkDataLocator(vec![]),
-                        tx.dst_index,
+    pub fn prepare_process<K: KvStore>(
+        &self,
+        db: &K,
+        ops: Vec<core::MpnOperation>,
+    ) -> Result<PreparedBatch<circuits::MpnCircuit>, BankError> {
+        let mut overlay = StateOverlay::new(db, STATE_MODEL_VERSION)?;
+        let mut transitions = Vec::new();
+        for op in ops.iter() {
+            transitions.push(match op {
+                core::MpnOperation::Transfer(tx) => {
+                    circuits::MpnTransition::Transfer(overlay.apply_token_transfer(tx)?)
+                }
+                core::MpnOperation::DepositWithdraw(tx) => {
+                    circuits::MpnTransition::DepositWithdraw(
+                        overlay.apply_token_deposit_withdraw(tx)?,
                     )
-                    .unwrap(),
-                );
-
-                let dst_after = core::Account {
-                    address: tx.dst_pub_key.0.decompress(),
-                    balance: dst_before.balance + tx.amount,
-                    nonce: dst_before.nonce,
-                };
-                set_account(&mut mirror, tx.dst_index, dst_after);
-
-                transitions.push(circuits::Transition {
-                    enabled: true,
-                    tx: tx.clone(),
-                    src_before,
-                    src_proof,
-                    dst_before,
-                    dst_proof,
-                });
-            }
+                }
+            });
         }
-
-        let next_state = KvStoreStateManager::<ZkHasher>::get_data(
-            &mirror,
-            *MPN_CONTRACT_ID,
-            &ZkDataLocator(vec![]),
-        )
-        .unwrap();
-        let aux_data = ZkScalar::from(0);
-
-        let circuit = circuits::UpdateCircuit {
+        overlay.into_batch(|state, aux_data, next_state| circuits::MpnCircuit {
             filled: true,
             state,
             aux_data,
             next_state,
-            transitions: Box::new(circuits::TransitionBatch::new(transitions)),
-        };
+            transitions: Box::new(circuits::MpnTransitionBatch::new(transitions)),
+        })
+    }
+    pub fn verify_process<C>(&self, batch: &PreparedBatch<C>, proof: &B::Proof) -> bool {
+        B::verify(
+            &self.mpn_vk,
+            batch.state,
+            batch.aux_data,
+            batch.next_state.state_hash,
+            proof,
+        )
+    }
 
-        let start = std::time::Instant::now();
-        let proof = unsafe {
-            std::mem::transmute::<bellman::groth16::Proof<Bls12>, bazuka::zk::groth16::Groth16Proof>(
-                groth16::create_random_proof(circuit, &self.update_params, &mut OsRng).unwrap(),
-            )
-        };
-        println!(
-            "Proving took: {}ms",
-            (std::time::Instant::now() - start).as_millis()
-        );
+    // Processes an ordered list of token transfers and deposit/withdraws in a
+    // single proof, on a state of the latest model version. There is no
+    // on-chain VK for the unified circuit yet.
+    pub fn process<K: KvStore>(
+        &self,
+        db: &K,
+        ops: Vec<core::MpnOperation>,
+    ) -> Result<
+        (
+            bazuka::zk::ZkDeltaPairs,
+            bazuka::zk::ZkCompressedState,
+            B::Proof,
+        ),
+        BankError,
+    > {
+        self.prove_process(self.prepare_process(db, ops)?)
+    }
+    pub fn prove_process(
+        &self,
+        batch: PreparedBatch<circuits::MpnCircuit>,
+    ) -> Result<
+        (
+            bazuka::zk::ZkDeltaPairs,
+            bazuka::zk::ZkCompressedState,
+            B::Proof,
+        ),
+        BankError,
+    > {
+        self.save_witness(witness::Witness::mpn(&batch.circuit));
+        let proof = Self::prove(&self.mpn_params, batch.circuit)?;
 
-        if bazuka::zk::groth16::groth16_verify(
-            &bazuka::config::blockchain::MPN_UPDATE_VK,
-            state,
-            aux_data,
-            next_state,
+        if B::verify(
+            &self.mpn_vk,
+            batch.state,
+            batch.aux_data,
+            batch.next_state.state_hash,
             &proof,
         ) {
-            Ok((
-                extract_delta(mirror.to_ops()),
-                bazuka::zk::ZkCompressedState {
-                    state_hash: next_state,
-                    state_size: 0,
-                },
-                proof,
-            ))
+            Ok((batch.delta, batch.next_state, proof))
         } else {
             Err(BankError::CannotProve)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{genesis_db, set_account};
+    use bazuka::crypto::{jubjub, ZkSignatureScheme};
+
+    #[test]
+    fn test_token_transfer_to_taken_slot() {
+        let (src_pk, src_sk) = jubjub::JubJub::<ZkHasher>::generate_keys(b"src");
+        let (dst_pk, _) = jubjub::JubJub::<ZkHasher>::generate_keys(b"dst");
+        let mut src = core::Account {
+            address: src_pk.0.decompress(),
+            ..Default::default()
+        };
+        src.tokens[0] = core::Token {
+            token_id: ZkScalar::from(7),
+            amount: 1000,
+        };
+        let mut dst = core::Account {
+            address: dst_pk.0.decompress(),
+            ..Default::default()
+        };
+        dst.tokens[0] = core::Token {
+            token_id: ZkScalar::from(8),
+            amount: 1000,
+        };
+        let mut db = genesis_db(STATE_MODEL_VERSION);
+        set_account(&mut db, STATE_MODEL_VERSION, 0, src).unwrap();
+        set_account(&mut db, STATE_MODEL_VERSION, 1, dst).unwrap();
+
+        let mut tx = core::TokenTransfer {
+            nonce: 0,
+            src_index: 0,
+            src_token_index: 0,
+            dst_index: 1,
+            dst_token_index: 0,
+            dst_pub_key: dst_pk,
+            token_id: ZkScalar::from(7),
+            amount: 100,
+            fee: 1,
+            sig: jubjub::Signature::default(),
+        };
+        tx.sign(src_sk);
+        let mut overlay = StateOverlay::new(&db, STATE_MODEL_VERSION).unwrap();
+        assert!(matches!(
+            overlay.apply_token_transfer(&tx),
+            Err(BankError::InvalidToken)
+        ));
+
+        // The source is left untouched
+        assert!(overlay.cache.root().is_none());
+        assert!(overlay.cache.delta().unwrap().0.is_empty());
+    }
+}
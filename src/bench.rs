@@ -0,0 +1,244 @@
+use crate::backend::ProvingBackend;
+use crate::bank::{Bank, BankError, PreparedBatch};
+use crate::config::BATCH_SIZE;
+use crate::{core, state};
+use bazuka::core::ZkHasher;
+use bazuka::crypto::{jubjub, ZkSignatureScheme};
+use bazuka::db::KvStore;
+use bazuka::zk::{DepositWithdraw, ZeroTransaction, ZkCompressedState, ZkDeltaPairs, ZkScalar};
+use bellman::{Circuit, ConstraintSystem, Index, LinearCombination, SynthesisError, Variable};
+use serde::Serialize;
+use std::time::Instant;
+use zeekit::BellmanFr;
+
+const INITIAL_BALANCE: u64 = 1_000_000;
+const TOKEN_ID: u64 = 1;
+
+// Timings of a single batch, in milliseconds. Witness generation includes
+// building the Merkle proofs, and proving includes the check the bank does on
+// the proof. `verified` is the result of verifying the proof separately.
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchResult {
+    pub circuit: String,
+    pub batch_size: usize,
+    pub constraints: usize,
+    pub witness_ms: f64,
+    pub synthesis_ms: f64,
+    pub proving_ms: f64,
+    pub verified: bool,
+}
+
+fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+// Evaluates the assignments of a filled circuit, like the prover does before
+// computing the proof.
+#[derive(Default)]
+struct AssignmentCs {
+    inputs: Vec<BellmanFr>,
+    aux: Vec<BellmanFr>,
+    constraints: usize,
+}
+
+impl ConstraintSystem<BellmanFr> for AssignmentCs {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(&mut self, _annotation: A, f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.aux.push(f()?);
+        Ok(Variable::new_unchecked(Index::Aux(self.aux.len() - 1)))
+    }
+
+    fn alloc_input<F, A, AR>(&mut self, _annotation: A, f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.inputs.push(f()?);
+        Ok(Variable::new_unchecked(Index::Input(self.inputs.len())))
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(&mut self, _annotation: A, _a: LA, _b: LB, _c: LC)
+    where
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+        LA: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LB: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LC: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+    {
+        self.constraints += 1;
+    }
+
+    fn push_namespace<NR, N>(&mut self, _name_fn: N)
+    where
+        NR: Into<String>,
+        N: FnOnce() -> NR,
+    {
+    }
+
+    fn pop_namespace(&mut self) {}
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
+
+// Returns the synthesis time and the number of constraints
+fn synthesize<C: Circuit<BellmanFr>>(circuit: C) -> (f64, usize) {
+    let start = Instant::now();
+    let mut cs = AssignmentCs::default();
+    circuit.synthesize(&mut cs).unwrap();
+    (elapsed_ms(start), cs.constraints)
+}
+
+// Prepares a batch once, and times its synthesis and proving
+fn measure<C: Circuit<BellmanFr> + Clone, P>(
+    circuit: &str,
+    batch_size: usize,
+    prepare: impl FnOnce() -> PreparedBatch<C>,
+    prove: impl FnOnce(PreparedBatch<C>) -> Result<(ZkDeltaPairs, ZkCompressedState, P), BankError>,
+    verify: impl FnOnce(&PreparedBatch<C>, &P) -> bool,
+) -> BenchResult {
+    let start = Instant::now();
+    let batch = prepare();
+    let witness_ms = elapsed_ms(start);
+    let (synthesis_ms, constraints) = synthesize(batch.circuit.clone());
+    let to_prove = batch.clone();
+    let start = Instant::now();
+    let proof = prove(to_prove);
+    let proving_ms = elapsed_ms(start);
+    BenchResult {
+        circuit: circuit.into(),
+        batch_size,
+        constraints,
+        witness_ms,
+        synthesis_ms,
+        proving_ms,
+        verified: proof.map_or(false, |(_, _, proof)| verify(&batch, &proof)),
+    }
+}
+
+// Runs every circuit with 1 to BATCH_SIZE enabled transitions, on a synthetic
+// state with `accounts` funded accounts.
+pub fn run<B: ProvingBackend>(bank: &Bank<B>, accounts: u32) -> Vec<BenchResult> {
+    let accounts = std::cmp::max(accounts, 2 * BATCH_SIZE as u32);
+    let keys = (0..accounts)
+        .map(|i| jubjub::JubJub::<ZkHasher>::generate_keys(format!("user-{}", i).as_bytes()))
+        .collect::<Vec<_>>();
+
+    let mut db = state::genesis_db(state::CHAIN_STATE_MODEL_VERSION);
+    let deposits = keys
+        .iter()
+        .enumerate()
+        .map(|(i, (pk, _))| DepositWithdraw {
+            index: i as u32,
+            pub_key: pk.clone(),
+            amount: INITIAL_BALANCE as i64,
+        })
+        .collect::<Vec<_>>();
+    for chunk in deposits.chunks(BATCH_SIZE) {
+        let batch = bank.prepare_deposit_withdraw(&db, chunk.to_vec()).unwrap();
+        db.update(&batch.ops).unwrap();
+    }
+
+    let token_deposits = keys
+        .iter()
+        .enumerate()
+        .map(|(i, (pk, _))| {
+            core::MpnOperation::DepositWithdraw(core::TokenDepositWithdraw {
+                index: i as u32,
+                token_index: 0,
+                pub_key: pk.clone(),
+                token_id: ZkScalar::from(TOKEN_ID),
+                amount: INITIAL_BALANCE as i64,
+            })
+        })
+        .collect::<Vec<_>>();
+    // Token operations are measured on a state of the latest model version
+    let mut token_db = state::genesis_db(state::STATE_MODEL_VERSION);
+    for chunk in token_deposits.chunks(BATCH_SIZE) {
+        let batch = bank.prepare_process(&token_db, chunk.to_vec()).unwrap();
+        token_db.update(&batch.ops).unwrap();
+    }
+
+    let mut results = Vec::new();
+    for batch_size in 1..=BATCH_SIZE {
+        let deposits = deposits[..batch_size].to_vec();
+        results.push(measure(
+            "deposit_withdraw",
+            batch_size,
+            || bank.prepare_deposit_withdraw(&db, deposits).unwrap(),
+            |batch| bank.prove_deposit_withdraw(batch),
+            |batch, proof| bank.verify_deposit_withdraw(batch, proof),
+        ));
+
+        let txs = (0..batch_size)
+            .map(|i| {
+                let dst = i + BATCH_SIZE;
+                let mut tx = ZeroTransaction {
+                    nonce: 0,
+                    src_index: i as u32,
+                    dst_index: dst as u32,
+                    dst_pub_key: keys[dst].0.clone(),
+                    amount: 100,
+                    fee: 1,
+                    sig: jubjub::Signature::default(),
+                };
+                tx.sign(keys[i].1.clone());
+                tx
+            })
+            .collect::<Vec<_>>();
+        results.push(measure(
+            "update",
+            batch_size,
+            || bank.prepare_change_state(&db, txs).unwrap(),
+            |batch| bank.prove_change_state(batch),
+            |batch, proof| bank.verify_change_state(batch, proof),
+        ));
+
+        // Alternating transfers and deposits
+        let ops = (0..batch_size)
+            .map(|i| {
+                if i % 2 == 0 {
+                    let dst = i + BATCH_SIZE;
+                    let mut tx = core::TokenTransfer {
+                        nonce: 0,
+                        src_index: i as u32,
+                        src_token_index: 0,
+                        dst_index: dst as u32,
+                        dst_token_index: 0,
+                        dst_pub_key: keys[dst].0.clone(),
+                        token_id: ZkScalar::from(TOKEN_ID),
+                        amount: 100,
+                        fee: 1,
+                        sig: jubjub::Signature::default(),
+                    };
+                    tx.sign(keys[i].1.clone());
+                    core::MpnOperation::Transfer(tx)
+                } else {
+                    core::MpnOperation::DepositWithdraw(core::TokenDepositWithdraw {
+                        index: i as u32,
+                        token_index: 0,
+                        pub_key: keys[i].0.clone(),
+                        token_id: ZkScalar::from(TOKEN_ID),
+                        amount: 100,
+                    })
+                }
+            })
+            .collect::<Vec<_>>();
+        results.push(measure(
+            "mpn",
+            batch_size,
+            || bank.prepare_process(&token_db, ops).unwrap(),
+            |batch| bank.prove_process(batch),
+            |batch, proof| bank.verify_process(batch, proof),
+        ));
+    }
+    results
+}
@@ -0,0 +1,178 @@
+use crate::config::LOG4_TREE_SIZE;
+use crate::core;
+use crate::state::{account_delta, check_account, get_account, StateError, StateModelVersion};
+use crate::tokens::tokens_root;
+use bazuka::core::ZkHasher;
+use bazuka::zk::ZkHasher as _;
+use bazuka::zk::{KvStoreStateManager, ZkDataLocator, ZkDeltaPairs, ZkScalar};
+use bazuka::{config::blockchain::MPN_CONTRACT_ID, db::KvStore};
+use std::collections::{HashMap, HashSet};
+use zeekit::merkle::Proof;
+
+// Leaf of an account in the MPN state tree, accounts of version 1 have no token
+// subtree.
+pub fn hash_account(version: StateModelVersion, acc: &core::Account) -> ZkScalar {
+    match version {
+        StateModelVersion::V1 => ZkHasher::hash(&[
+            ZkScalar::from(acc.nonce),
+            acc.address.0,
+            acc.address.1,
+            ZkScalar::from(acc.balance),
+        ]),
+        StateModelVersion::V2 => ZkHasher::hash(&[
+            ZkScalar::from(acc.nonce),
+            acc.address.0,
+            acc.address.1,
+            ZkScalar::from(acc.balance),
+            tokens_root(acc),
+        ]),
+    }
+}
+
+// Overlay over the MPN state of a database, used while building a witness.
+// The path of an account is read from the state manager only once, after that
+// its proofs are built from the cached nodes, which are updated incrementally
+// when accounts are set. Changes are extracted with `delta`.
+//
+// Every node that differs from the database is cached (Along with its
+// siblings), so cached nodes always take priority over the database. Accounts
+// are hashed by the model version of the state.
+#[derive(Debug, Clone)]
+pub struct MerkleCache {
+    version: StateModelVersion,
+    accounts: HashMap<u32, core::Account>,
+    nodes: HashMap<(u8, u64), ZkScalar>,
+    seeded: HashSet<u32>,
+    dirty: HashSet<u32>,
+}
+
+impl MerkleCache {
+    pub fn new(version: StateModelVersion) -> Self {
+        Self {
+            version,
+            accounts: HashMap::new(),
+            nodes: HashMap::new(),
+            seeded: HashSet::new(),
+            dirty: HashSet::new(),
+        }
+    }
+
+    pub fn version(&self) -> StateModelVersion {
+        self.version
+    }
+
+    fn seed<K: KvStore>(&mut self, db: &K, index: u32) -> Result<(), StateError> {
+        if self.seeded.contains(&index) {
+            return Ok(());
+        }
+        let proof = KvStoreStateManager::<ZkHasher>::prove(
+            db,
+            *MPN_CONTRACT_ID,
+            ZkDataLocator(vec![]),
+            index,
+        )?;
+        let mut val = KvStoreStateManager::<ZkHasher>::get_data(
+            db,
+            *MPN_CONTRACT_ID,
+            &ZkDataLocator(vec![index]),
+        )?;
+        self.seeded.insert(index);
+        let mut index = index as u64;
+        for (level, siblings) in proof.iter().enumerate() {
+            let level = level as u8;
+            let first = index - index % 4;
+            let mut it = siblings.iter();
+            let mut vals = [ZkScalar::from(0); 4];
+            for (i, v) in vals.iter_mut().enumerate() {
+                let pos = first + i as u64;
+                let db_val = if pos == index {
+                    val
+                } else {
+                    *it.next().unwrap()
+                };
+                *v = *self.nodes.entry((level, pos)).or_insert(db_val);
+            }
+            val = ZkHasher::hash(&vals);
+            index /= 4;
+        }
+        self.nodes.entry((LOG4_TREE_SIZE, 0)).or_insert(val);
+        Ok(())
+    }
+
+    pub fn get_account<K: KvStore>(
+        &mut self,
+        db: &K,
+        index: u32,
+    ) -> Result<core::Account, StateError> {
+        if let Some(acc) = self.accounts.get(&index) {
+            return Ok(acc.clone());
+        }
+        let acc = get_account(db, self.version, index)?;
+        self.accounts.insert(index, acc.clone());
+        Ok(acc)
+    }
+
+    pub fn prove<K: KvStore>(
+        &mut self,
+        db: &K,
+        index: u32,
+    ) -> Result<Proof<LOG4_TREE_SIZE>, StateError> {
+        self.seed(db, index)?;
+        let mut index = index as u64;
+        let mut proof = Vec::new();
+        for level in 0..LOG4_TREE_SIZE {
+            let first = index - index % 4;
+            let siblings = (first..first + 4)
+                .filter(|i| *i != index)
+                .map(|i| self.nodes[&(level, i)])
+                .collect::<Vec<_>>();
+            proof.push(siblings.try_into().unwrap());
+            index /= 4;
+        }
+        Ok(Proof(proof))
+    }
+
+    // Accounts that do not fit in the state model are rejected, leaving the
+    // cache untouched.
+    pub fn set_account<K: KvStore>(
+        &mut self,
+        db: &K,
+        index: u32,
+        acc: core::Account,
+    ) -> Result<(), StateError> {
+        check_account(self.version, index, &acc)?;
+        self.seed(db, index)?;
+        let mut val = hash_account(self.version, &acc);
+        self.accounts.insert(index, acc);
+        self.dirty.insert(index);
+
+        let mut index = index as u64;
+        for level in 0..LOG4_TREE_SIZE {
+            self.nodes.insert((level, index), val);
+            let first = index - index % 4;
+            let mut vals = [ZkScalar::from(0); 4];
+            for (i, v) in vals.iter_mut().enumerate() {
+                *v = self.nodes[&(level, first + i as u64)];
+            }
+            val = ZkHasher::hash(&vals);
+            index /= 4;
+        }
+        self.nodes.insert((LOG4_TREE_SIZE, 0), val);
+        Ok(())
+    }
+
+    // Root of the state with the cached changes applied, if any account is cached
+    pub fn root(&self) -> Option<ZkScalar> {
+        self.nodes.get(&(LOG4_TREE_SIZE, 0)).cloned()
+    }
+
+    // Delta of the changed accounts
+    pub fn delta(&self) -> Result<ZkDeltaPairs, StateError> {
+        account_delta(
+            self.version,
+            self.dirty
+                .iter()
+                .map(|index| (*index, &self.accounts[index])),
+        )
+    }
+}
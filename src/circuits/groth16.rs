@@ -31,6 +31,723 @@ fn alloc_point<CS: ConstraintSystem<BellmanFr>>(
     })
 }
 
+// Applies a single transfer on `state_wit`, returning the new state (Or the same
+// state, when the transition is disabled).
+fn transfer<CS: ConstraintSystem<BellmanFr>>(
+    cs: &mut CS,
+    filled: bool,
+    enabled_wit: AllocatedBit,
+    state_wit: &AllocatedNum<BellmanFr>,
+    trans: &Transition,
+) -> Result<AllocatedNum<BellmanFr>, SynthesisError> {
+    let src_nonce_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.src_before.nonce))?;
+    let src_addr_wit = alloc_point(&mut *cs, filled, trans.src_before.address)?;
+    let src_balance_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.src_before.balance))?;
+    let src_hash_wit = poseidon::groth16::poseidon(
+        &mut cs.namespace(|| "poseidon"),
+        &[
+            src_nonce_wit.clone(),
+            src_addr_wit.x.clone(),
+            src_addr_wit.y.clone(),
+            src_balance_wit.clone(),
+        ],
+    )?;
+    let mut src_proof_wits = Vec::new();
+    for b in trans.src_proof.0.clone() {
+        src_proof_wits.push([
+            alloc_num(&mut *cs, filled, b[0])?,
+            alloc_num(&mut *cs, filled, b[1])?,
+            alloc_num(&mut *cs, filled, b[2])?,
+        ]);
+    }
+
+    let tx_nonce_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.nonce))?;
+    let tx_src_index_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.src_index as u64))?;
+    let tx_dst_index_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.dst_index as u64))?;
+    let tx_dst_addr_wit = alloc_point(&mut *cs, filled, trans.tx.dst_pub_key.0.decompress())?;
+    let tx_amount_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.amount))?;
+    let tx_fee_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.fee))?;
+    let tx_hash_wit = poseidon::groth16::poseidon(
+        &mut cs.namespace(|| "poseidon"),
+        &[
+            tx_nonce_wit.clone(),
+            tx_src_index_wit.clone(),
+            tx_dst_index_wit.clone(),
+            tx_amount_wit.clone(),
+            tx_fee_wit.clone(),
+        ],
+    )?;
+    let tx_sig_r_wit = alloc_point(&mut *cs, filled, trans.tx.sig.r)?;
+    let tx_sig_s_wit = alloc_num(&mut *cs, filled, trans.tx.sig.s)?;
+
+    let new_src_nonce_wit =
+        alloc_num(&mut *cs, filled, ZkScalar::from(trans.src_before.nonce + 1))?;
+    cs.enforce(
+        || "",
+        |lc| lc + src_nonce_wit.get_variable() + CS::one(),
+        |lc| lc + CS::one(),
+        |lc| lc + new_src_nonce_wit.get_variable(),
+    );
+    let new_src_balance_wit = alloc_num(
+        &mut *cs,
+        filled,
+        ZkScalar::from(trans.src_before.balance - trans.tx.amount - trans.tx.fee),
+    )?;
+    cs.enforce(
+        || "",
+        |lc| {
+            lc + src_balance_wit.get_variable()
+                - tx_amount_wit.get_variable()
+                - tx_fee_wit.get_variable()
+        },
+        |lc| lc + CS::one(),
+        |lc| lc + new_src_balance_wit.get_variable(),
+    );
+    let new_src_hash_wit = poseidon::groth16::poseidon(
+        &mut cs.namespace(|| "poseidon"),
+        &[
+            new_src_nonce_wit,
+            src_addr_wit.x.clone(),
+            src_addr_wit.y.clone(),
+            new_src_balance_wit,
+        ],
+    )?;
+
+    let middle_root_wit = merkle::groth16::calc_root_poseidon4(
+        &mut cs.namespace(|| "calc_root_poseidon4"),
+        tx_src_index_wit.clone(),
+        new_src_hash_wit,
+        src_proof_wits.clone(),
+    )?;
+
+    let dst_nonce_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.dst_before.nonce))?;
+    let dst_addr_wit = alloc_point(&mut *cs, filled, trans.dst_before.address)?;
+    let dst_balance_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.dst_before.balance))?;
+    let dst_hash_wit = poseidon::groth16::poseidon(
+        &mut cs.namespace(|| "poseidon"),
+        &[
+            dst_nonce_wit.clone(),
+            dst_addr_wit.x.clone(),
+            dst_addr_wit.y.clone(),
+            dst_balance_wit.clone(),
+        ],
+    )?;
+    let mut dst_proof_wits = Vec::new();
+    for b in trans.dst_proof.0.clone() {
+        dst_proof_wits.push([
+            alloc_num(&mut *cs, filled, b[0])?,
+            alloc_num(&mut *cs, filled, b[1])?,
+            alloc_num(&mut *cs, filled, b[2])?,
+        ]);
+    }
+
+    let new_dst_balance_wit = alloc_num(
+        &mut *cs,
+        filled,
+        ZkScalar::from(trans.dst_before.balance + trans.tx.amount),
+    )?;
+    cs.enforce(
+        || "",
+        |lc| lc + dst_balance_wit.get_variable() + tx_amount_wit.get_variable(),
+        |lc| lc + CS::one(),
+        |lc| lc + new_dst_balance_wit.get_variable(),
+    );
+
+    // enforce dst_addr_wit == tx_dst_addr_wit or zero!
+    cs.enforce(
+        || "",
+        |lc| lc + dst_addr_wit.x.get_variable(),
+        |lc| lc + dst_addr_wit.x.get_variable() - tx_dst_addr_wit.x.get_variable(),
+        |lc| lc,
+    );
+    cs.enforce(
+        || "",
+        |lc| lc + dst_addr_wit.y.get_variable(),
+        |lc| lc + dst_addr_wit.y.get_variable() - tx_dst_addr_wit.y.get_variable(),
+        |lc| lc,
+    );
+
+    let new_dst_hash_wit = poseidon::groth16::poseidon(
+        &mut cs.namespace(|| "poseidon"),
+        &[
+            dst_nonce_wit,
+            tx_dst_addr_wit.x,
+            tx_dst_addr_wit.y,
+            new_dst_balance_wit,
+        ],
+    )?;
+
+    merkle::groth16::check_proof_poseidon4(
+        &mut cs.namespace(|| "check_proof_poseidon4"),
+        enabled_wit.clone(),
+        tx_dst_index_wit.clone(),
+        dst_hash_wit,
+        dst_proof_wits.clone(),
+        middle_root_wit,
+    )?;
+    merkle::groth16::check_proof_poseidon4(
+        &mut cs.namespace(|| "check_proof_poseidon4"),
+        enabled_wit.clone(),
+        tx_src_index_wit,
+        src_hash_wit,
+        src_proof_wits,
+        state_wit.clone(),
+    )?;
+
+    // WARN: MIGHT OVERFLOW!
+    let tx_balance_plus_fee = alloc_num(
+        &mut *cs,
+        filled,
+        ZkScalar::from(trans.tx.amount + trans.tx.fee),
+    )?;
+    cs.enforce(
+        || "",
+        |lc| lc + tx_amount_wit.get_variable() + tx_fee_wit.get_variable(),
+        |lc| lc + CS::one(),
+        |lc| lc + tx_balance_plus_fee.get_variable(),
+    );
+    common::groth16::lte(
+        &mut cs.namespace(|| "lte"),
+        tx_balance_plus_fee,
+        src_balance_wit,
+    )?;
+
+    cs.enforce(
+        || "",
+        |lc| lc + tx_nonce_wit.get_variable(),
+        |lc| lc + CS::one(),
+        |lc| lc + src_nonce_wit.get_variable(),
+    );
+
+    eddsa::groth16::verify_eddsa(
+        &mut cs.namespace(|| "verify_eddsa"),
+        enabled_wit.clone(),
+        src_addr_wit,
+        tx_hash_wit,
+        tx_sig_r_wit,
+        tx_sig_s_wit,
+    )?;
+
+    let next_state_wit = merkle::groth16::calc_root_poseidon4(
+        &mut cs.namespace(|| "calc_root_poseidon4"),
+        tx_dst_index_wit,
+        new_dst_hash_wit,
+        dst_proof_wits,
+    )?;
+
+    Ok(AllocatedNum::conditionally_reverse(
+        &mut *cs,
+        state_wit,
+        &next_state_wit,
+        &Boolean::Is(enabled_wit),
+    )?
+    .0)
+}
+
+// Applies a single deposit/withdraw on `state_wit`, returning the new state (Or the
+// same state, when the transition is disabled).
+fn deposit_withdraw<CS: ConstraintSystem<BellmanFr>>(
+    cs: &mut CS,
+    filled: bool,
+    enabled_wit: AllocatedBit,
+    state_wit: &AllocatedNum<BellmanFr>,
+    trans: &DepositWithdrawTransition,
+) -> Result<AllocatedNum<BellmanFr>, SynthesisError> {
+    let src_nonce_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.before.nonce))?;
+    let src_addr_wit = alloc_point(&mut *cs, filled, trans.before.address)?;
+    let src_balance_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.before.balance))?;
+    let src_hash_wit = poseidon::groth16::poseidon(
+        &mut cs.namespace(|| "poseidon"),
+        &[
+            src_nonce_wit.clone(),
+            src_addr_wit.x.clone(),
+            src_addr_wit.y.clone(),
+            src_balance_wit.clone(),
+        ],
+    )?;
+
+    let mut proof_wits = Vec::new();
+    for b in trans.proof.0.clone() {
+        proof_wits.push([
+            alloc_num(&mut *cs, filled, b[0])?,
+            alloc_num(&mut *cs, filled, b[1])?,
+            alloc_num(&mut *cs, filled, b[2])?,
+        ]);
+    }
+
+    let tx_index_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.index as u64))?;
+    let tx_pub_key_wit = alloc_point(&mut *cs, filled, trans.tx.pub_key.0.decompress())?;
+    let tx_amount_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.amount as u64))?;
+
+    // enforce src_addr_wit == tx_pub_key_wit or zero!
+    cs.enforce(
+        || "",
+        |lc| lc + src_addr_wit.x.get_variable(),
+        |lc| lc + src_addr_wit.x.get_variable() - tx_pub_key_wit.x.get_variable(),
+        |lc| lc,
+    );
+    cs.enforce(
+        || "",
+        |lc| lc + src_addr_wit.y.get_variable(),
+        |lc| lc + src_addr_wit.y.get_variable() - tx_pub_key_wit.y.get_variable(),
+        |lc| lc,
+    );
+
+    merkle::groth16::check_proof_poseidon4(
+        &mut cs.namespace(|| "check_proof_poseidon4"),
+        enabled_wit.clone(),
+        tx_index_wit.clone(),
+        src_hash_wit,
+        proof_wits.clone(),
+        state_wit.clone(),
+    )?;
+
+    let balance_bits =
+        common::groth16::to_bits(&mut cs.namespace(|| "to_bits"), src_balance_wit, 64)?;
+    let amount_bits = common::groth16::to_bits(&mut cs.namespace(|| "to_bits"), tx_amount_wit, 64)?;
+    let balance_amount_sum =
+        common::groth16::sum_bits(&mut cs.namespace(|| "sum_bits"), balance_bits, amount_bits)?;
+    let balance_amount_sum_bits =
+        common::groth16::to_bits(&mut cs.namespace(|| "to_bits"), balance_amount_sum, 65)?;
+    let new_balance_wit = common::groth16::from_bits(
+        &mut cs.namespace(|| "from_bits"),
+        balance_amount_sum_bits[..64].to_vec(),
+    )?;
+
+    let new_hash_wit = poseidon::groth16::poseidon(
+        &mut cs.namespace(|| "poseidon"),
+        &[
+            src_nonce_wit,
+            tx_pub_key_wit.x.clone(),
+            tx_pub_key_wit.y.clone(),
+            new_balance_wit,
+        ],
+    )?;
+
+    let next_state_wit = merkle::groth16::calc_root_poseidon4(
+        &mut cs.namespace(|| "calc_root_poseidon4"),
+        tx_index_wit,
+        new_hash_wit,
+        proof_wits,
+    )?;
+
+    Ok(AllocatedNum::conditionally_reverse(
+        &mut *cs,
+        state_wit,
+        &next_state_wit,
+        &Boolean::Is(enabled_wit),
+    )?
+    .0)
+}
+
+fn alloc_proof<CS: ConstraintSystem<BellmanFr>, const LOG4_SIZE: u8>(
+    cs: &mut CS,
+    filled: bool,
+    proof: &merkle::Proof<LOG4_SIZE>,
+) -> Result<Vec<[AllocatedNum<BellmanFr>; 3]>, SynthesisError> {
+    let mut proof_wits = Vec::new();
+    for b in proof.0.clone() {
+        proof_wits.push([
+            alloc_num(&mut *cs, filled, b[0])?,
+            alloc_num(&mut *cs, filled, b[1])?,
+            alloc_num(&mut *cs, filled, b[2])?,
+        ]);
+    }
+    Ok(proof_wits)
+}
+
+// Token leaves of an account, allocated along with the token proof
+struct AllocatedTokenLeaf {
+    index: AllocatedNum<BellmanFr>,
+    token_id: AllocatedNum<BellmanFr>,
+    amount: AllocatedNum<BellmanFr>,
+    proof: Vec<[AllocatedNum<BellmanFr>; 3]>,
+}
+
+fn alloc_token_leaf<CS: ConstraintSystem<BellmanFr>>(
+    cs: &mut CS,
+    filled: bool,
+    acc: &core::Account,
+    token_index: u32,
+    proof: &merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+) -> Result<AllocatedTokenLeaf, SynthesisError> {
+    let token = acc
+        .tokens
+        .get(token_index as usize)
+        .cloned()
+        .unwrap_or_default();
+    Ok(AllocatedTokenLeaf {
+        index: alloc_num(&mut *cs, filled, ZkScalar::from(token_index as u64))?,
+        token_id: alloc_num(&mut *cs, filled, token.token_id)?,
+        amount: alloc_num(&mut *cs, filled, ZkScalar::from(token.amount))?,
+        proof: alloc_proof(&mut *cs, filled, proof)?,
+    })
+}
+
+// Root of the token subtree of an account, where the token leaf is replaced with
+// the given token-id and amount.
+fn calc_tokens_root<CS: ConstraintSystem<BellmanFr>>(
+    cs: &mut CS,
+    leaf: &AllocatedTokenLeaf,
+    token_id: AllocatedNum<BellmanFr>,
+    amount: AllocatedNum<BellmanFr>,
+) -> Result<AllocatedNum<BellmanFr>, SynthesisError> {
+    let token_hash_wit =
+        poseidon::groth16::poseidon(&mut cs.namespace(|| "poseidon"), &[token_id, amount])?;
+    merkle::groth16::calc_root_poseidon4(
+        &mut cs.namespace(|| "calc_root_poseidon4"),
+        leaf.index.clone(),
+        token_hash_wit,
+        leaf.proof.clone(),
+    )
+}
+
+// enforce token_id == tx_token_id or amount zero!
+fn enforce_token_slot<CS: ConstraintSystem<BellmanFr>>(
+    cs: &mut CS,
+    leaf: &AllocatedTokenLeaf,
+    tx_token_id_wit: &AllocatedNum<BellmanFr>,
+) {
+    cs.enforce(
+        || "",
+        |lc| lc + leaf.amount.get_variable(),
+        |lc| lc + leaf.token_id.get_variable() - tx_token_id_wit.get_variable(),
+        |lc| lc,
+    );
+}
+
+// Destination of a token operation, the credit of a transfer should be the one
+// signed by the source.
+struct AllocatedCredit {
+    index: AllocatedNum<BellmanFr>,
+    pub_key: AllocatedPoint,
+    token_id: AllocatedNum<BellmanFr>,
+    amount: AllocatedNum<BellmanFr>,
+}
+
+// enforce a == b, if enabled!
+fn enforce_equal_if<CS: ConstraintSystem<BellmanFr>>(
+    cs: &mut CS,
+    enabled_wit: &AllocatedBit,
+    a: &AllocatedNum<BellmanFr>,
+    b: &AllocatedNum<BellmanFr>,
+) {
+    cs.enforce(
+        || "",
+        |lc| lc + a.get_variable() - b.get_variable(),
+        |lc| lc + enabled_wit.get_variable(),
+        |lc| lc,
+    );
+}
+
+// The source half of `transfer`, on accounts with token subtrees: checks the
+// signature, nonce and token balance of the source and returns the state after
+// debiting it (Or the same state, when the transition is disabled), along with
+// the destination the source signed. The destination is credited separately,
+// by `token_deposit_withdraw`.
+fn token_debit<CS: ConstraintSystem<BellmanFr>>(
+    cs: &mut CS,
+    filled: bool,
+    enabled_wit: AllocatedBit,
+    state_wit: &AllocatedNum<BellmanFr>,
+    trans: &TokenTransition,
+) -> Result<(AllocatedNum<BellmanFr>, AllocatedCredit), SynthesisError> {
+    let src_nonce_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.src_before.nonce))?;
+    let src_addr_wit = alloc_point(&mut *cs, filled, trans.src_before.address)?;
+    let src_balance_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.src_before.balance))?;
+    let src_token_wit = alloc_token_leaf(
+        &mut *cs,
+        filled,
+        &trans.src_before,
+        trans.tx.src_token_index,
+        &trans.src_token_proof,
+    )?;
+    let src_tokens_root_wit = calc_tokens_root(
+        &mut cs.namespace(|| "calc_tokens_root"),
+        &src_token_wit,
+        src_token_wit.token_id.clone(),
+        src_token_wit.amount.clone(),
+    )?;
+    let src_hash_wit = poseidon::groth16::poseidon(
+        &mut cs.namespace(|| "poseidon"),
+        &[
+            src_nonce_wit.clone(),
+            src_addr_wit.x.clone(),
+            src_addr_wit.y.clone(),
+            src_balance_wit.clone(),
+            src_tokens_root_wit,
+        ],
+    )?;
+    let src_proof_wits = alloc_proof(&mut *cs, filled, &trans.src_proof)?;
+
+    let tx_nonce_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.nonce))?;
+    let tx_src_index_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.src_index as u64))?;
+    let tx_dst_index_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.dst_index as u64))?;
+    let tx_dst_addr_wit = alloc_point(&mut *cs, filled, trans.tx.dst_pub_key.0.decompress())?;
+    let tx_token_id_wit = alloc_num(&mut *cs, filled, trans.tx.token_id)?;
+    let tx_amount_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.amount))?;
+    let tx_fee_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.fee))?;
+    let tx_hash_wit = poseidon::groth16::poseidon(
+        &mut cs.namespace(|| "poseidon"),
+        &[
+            tx_nonce_wit.clone(),
+            tx_src_index_wit.clone(),
+            tx_dst_index_wit.clone(),
+            tx_token_id_wit.clone(),
+            tx_amount_wit.clone(),
+            tx_fee_wit.clone(),
+        ],
+    )?;
+    let tx_sig_r_wit = alloc_point(&mut *cs, filled, trans.tx.sig.r)?;
+    let tx_sig_s_wit = alloc_num(&mut *cs, filled, trans.tx.sig.s)?;
+
+    // enforce src_token_id == tx_token_id
+    cs.enforce(
+        || "",
+        |lc| lc + src_token_wit.token_id.get_variable(),
+        |lc| lc + CS::one(),
+        |lc| lc + tx_token_id_wit.get_variable(),
+    );
+
+    let src_amount = trans
+        .src_before
+        .tokens
+        .get(trans.tx.src_token_index as usize)
+        .map(|t| t.amount)
+        .unwrap_or_default();
+    let new_src_nonce_wit =
+        alloc_num(&mut *cs, filled, ZkScalar::from(trans.src_before.nonce + 1))?;
+    cs.enforce(
+        || "",
+        |lc| lc + src_nonce_wit.get_variable() + CS::one(),
+        |lc| lc + CS::one(),
+        |lc| lc + new_src_nonce_wit.get_variable(),
+    );
+    let new_src_amount_wit = alloc_num(
+        &mut *cs,
+        filled,
+        ZkScalar::from(src_amount.wrapping_sub(trans.tx.amount + trans.tx.fee)),
+    )?;
+    cs.enforce(
+        || "",
+        |lc| {
+            lc + src_token_wit.amount.get_variable()
+                - tx_amount_wit.get_variable()
+                - tx_fee_wit.get_variable()
+        },
+        |lc| lc + CS::one(),
+        |lc| lc + new_src_amount_wit.get_variable(),
+    );
+    let new_src_tokens_root_wit = calc_tokens_root(
+        &mut cs.namespace(|| "calc_tokens_root"),
+        &src_token_wit,
+        tx_token_id_wit.clone(),
+        new_src_amount_wit,
+    )?;
+    let new_src_hash_wit = poseidon::groth16::poseidon(
+        &mut cs.namespace(|| "poseidon"),
+        &[
+            new_src_nonce_wit,
+            src_addr_wit.x.clone(),
+            src_addr_wit.y.clone(),
+            src_balance_wit,
+            new_src_tokens_root_wit,
+        ],
+    )?;
+
+    let middle_root_wit = merkle::groth16::calc_root_poseidon4(
+        &mut cs.namespace(|| "calc_root_poseidon4"),
+        tx_src_index_wit.clone(),
+        new_src_hash_wit,
+        src_proof_wits.clone(),
+    )?;
+    merkle::groth16::check_proof_poseidon4(
+        &mut cs.namespace(|| "check_proof_poseidon4"),
+        enabled_wit.clone(),
+        tx_src_index_wit,
+        src_hash_wit,
+        src_proof_wits,
+        state_wit.clone(),
+    )?;
+
+    // WARN: MIGHT OVERFLOW!
+    let tx_amount_plus_fee = alloc_num(
+        &mut *cs,
+        filled,
+        ZkScalar::from(trans.tx.amount + trans.tx.fee),
+    )?;
+    cs.enforce(
+        || "",
+        |lc| lc + tx_amount_wit.get_variable() + tx_fee_wit.get_variable(),
+        |lc| lc + CS::one(),
+        |lc| lc + tx_amount_plus_fee.get_variable(),
+    );
+    common::groth16::lte(
+        &mut cs.namespace(|| "lte"),
+        tx_amount_plus_fee,
+        src_token_wit.amount.clone(),
+    )?;
+
+    cs.enforce(
+        || "",
+        |lc| lc + tx_nonce_wit.get_variable(),
+        |lc| lc + CS::one(),
+        |lc| lc + src_nonce_wit.get_variable(),
+    );
+
+    eddsa::groth16::verify_eddsa(
+        &mut cs.namespace(|| "verify_eddsa"),
+        enabled_wit.clone(),
+        src_addr_wit,
+        tx_hash_wit,
+        tx_sig_r_wit,
+        tx_sig_s_wit,
+    )?;
+
+    Ok((
+        AllocatedNum::conditionally_reverse(
+            &mut *cs,
+            state_wit,
+            &middle_root_wit,
+            &Boolean::Is(enabled_wit),
+        )?
+        .0,
+        AllocatedCredit {
+            index: tx_dst_index_wit,
+            pub_key: tx_dst_addr_wit,
+            token_id: tx_token_id_wit,
+            amount: tx_amount_wit,
+        },
+    ))
+}
+
+// Same as `deposit_withdraw`, on accounts with token subtrees. Also credits the
+// destination of a transfer, returning the destination along with the state.
+fn token_deposit_withdraw<CS: ConstraintSystem<BellmanFr>>(
+    cs: &mut CS,
+    filled: bool,
+    enabled_wit: AllocatedBit,
+    state_wit: &AllocatedNum<BellmanFr>,
+    trans: &TokenDepositWithdrawTransition,
+) -> Result<(AllocatedNum<BellmanFr>, AllocatedCredit), SynthesisError> {
+    let src_nonce_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.before.nonce))?;
+    let src_addr_wit = alloc_point(&mut *cs, filled, trans.before.address)?;
+    let src_balance_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.before.balance))?;
+    let token_wit = alloc_token_leaf(
+        &mut *cs,
+        filled,
+        &trans.before,
+        trans.tx.token_index,
+        &trans.token_proof,
+    )?;
+    let tokens_root_wit = calc_tokens_root(
+        &mut cs.namespace(|| "calc_tokens_root"),
+        &token_wit,
+        token_wit.token_id.clone(),
+        token_wit.amount.clone(),
+    )?;
+    let src_hash_wit = poseidon::groth16::poseidon(
+        &mut cs.namespace(|| "poseidon"),
+        &[
+            src_nonce_wit.clone(),
+            src_addr_wit.x.clone(),
+            src_addr_wit.y.clone(),
+            src_balance_wit.clone(),
+            tokens_root_wit,
+        ],
+    )?;
+
+    let proof_wits = alloc_proof(&mut *cs, filled, &trans.proof)?;
+
+    let tx_index_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.index as u64))?;
+    let tx_pub_key_wit = alloc_point(&mut *cs, filled, trans.tx.pub_key.0.decompress())?;
+    let tx_token_id_wit = alloc_num(&mut *cs, filled, trans.tx.token_id)?;
+    let tx_amount_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.amount as u64))?;
+
+    // enforce src_addr_wit == tx_pub_key_wit or zero!
+    cs.enforce(
+        || "",
+        |lc| lc + src_addr_wit.x.get_variable(),
+        |lc| lc + src_addr_wit.x.get_variable() - tx_pub_key_wit.x.get_variable(),
+        |lc| lc,
+    );
+    cs.enforce(
+        || "",
+        |lc| lc + src_addr_wit.y.get_variable(),
+        |lc| lc + src_addr_wit.y.get_variable() - tx_pub_key_wit.y.get_variable(),
+        |lc| lc,
+    );
+    enforce_token_slot(&mut *cs, &token_wit, &tx_token_id_wit);
+
+    merkle::groth16::check_proof_poseidon4(
+        &mut cs.namespace(|| "check_proof_poseidon4"),
+        enabled_wit.clone(),
+        tx_index_wit.clone(),
+        src_hash_wit,
+        proof_wits.clone(),
+        state_wit.clone(),
+    )?;
+
+    let amount_bits = common::groth16::to_bits(
+        &mut cs.namespace(|| "to_bits"),
+        token_wit.amount.clone(),
+        64,
+    )?;
+    let tx_amount_bits =
+        common::groth16::to_bits(&mut cs.namespace(|| "to_bits"), tx_amount_wit.clone(), 64)?;
+    let amount_sum = common::groth16::sum_bits(
+        &mut cs.namespace(|| "sum_bits"),
+        amount_bits,
+        tx_amount_bits,
+    )?;
+    let amount_sum_bits =
+        common::groth16::to_bits(&mut cs.namespace(|| "to_bits"), amount_sum, 65)?;
+    let new_amount_wit = common::groth16::from_bits(
+        &mut cs.namespace(|| "from_bits"),
+        amount_sum_bits[..64].to_vec(),
+    )?;
+
+    let new_tokens_root_wit = calc_tokens_root(
+        &mut cs.namespace(|| "calc_tokens_root"),
+        &token_wit,
+        tx_token_id_wit.clone(),
+        new_amount_wit,
+    )?;
+    let new_hash_wit = poseidon::groth16::poseidon(
+        &mut cs.namespace(|| "poseidon"),
+        &[
+            src_nonce_wit,
+            tx_pub_key_wit.x.clone(),
+            tx_pub_key_wit.y.clone(),
+            src_balance_wit,
+            new_tokens_root_wit,
+        ],
+    )?;
+
+    let next_state_wit = merkle::groth16::calc_root_poseidon4(
+        &mut cs.namespace(|| "calc_root_poseidon4"),
+        tx_index_wit.clone(),
+        new_hash_wit,
+        proof_wits,
+    )?;
+
+    Ok((
+        AllocatedNum::conditionally_reverse(
+            &mut *cs,
+            state_wit,
+            &next_state_wit,
+            &Boolean::Is(enabled_wit),
+        )?
+        .0,
+        AllocatedCredit {
+            index: tx_index_wit,
+            pub_key: tx_pub_key_wit,
+            token_id: tx_token_id_wit,
+            amount: tx_amount_wit,
+        },
+    ))
+}
+
 impl Circuit<BellmanFr> for UpdateCircuit {
     fn synthesize<CS: ConstraintSystem<BellmanFr>>(
         self,
@@ -39,10 +756,10 @@ impl Circuit<BellmanFr> for UpdateCircuit {
         let filled = self.filled;
 
         let mut state_wit = alloc_num(&mut *cs, filled, self.state)?;
-        state_wit.inputize(&mut *cs)?;
+        state_wit.inputize(cs.namespace(|| "state"))?;
 
         let aux_wit = alloc_num(&mut *cs, filled, self.aux_data)?;
-        aux_wit.inputize(&mut *cs)?;
+        aux_wit.inputize(cs.namespace(|| "aux_data"))?;
         cs.enforce(
             || "",
             |lc| lc + aux_wit.get_variable(),
@@ -50,218 +767,53 @@ impl Circuit<BellmanFr> for UpdateCircuit {
             |lc| lc + aux_wit.get_variable(),
         );
 
-        for trans in self.transitions.0.iter() {
+        for (i, trans) in self.transitions.0.iter().enumerate() {
+            let cs = &mut cs.namespace(|| format!("transition {}", i));
             let enabled_wit = AllocatedBit::alloc(&mut *cs, filled.then(|| trans.enabled))?;
+            state_wit = transfer(&mut *cs, filled, enabled_wit, &state_wit, trans)?;
+        }
 
-            let src_nonce_wit =
-                alloc_num(&mut *cs, filled, ZkScalar::from(trans.src_before.nonce))?;
-            let src_addr_wit = alloc_point(&mut *cs, filled, trans.src_before.address)?;
-            let src_balance_wit =
-                alloc_num(&mut *cs, filled, ZkScalar::from(trans.src_before.balance))?;
-            let src_hash_wit = poseidon::groth16::poseidon(
-                &mut *cs,
-                &[
-                    src_nonce_wit.clone(),
-                    src_addr_wit.x.clone(),
-                    src_addr_wit.y.clone(),
-                    src_balance_wit.clone(),
-                ],
-            )?;
-            let mut src_proof_wits = Vec::new();
-            for b in trans.src_proof.0.clone() {
-                src_proof_wits.push([
-                    alloc_num(&mut *cs, filled, b[0])?,
-                    alloc_num(&mut *cs, filled, b[1])?,
-                    alloc_num(&mut *cs, filled, b[2])?,
-                ]);
-            }
-
-            let tx_nonce_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.nonce))?;
-            let tx_src_index_wit =
-                alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.src_index as u64))?;
-            let tx_dst_index_wit =
-                alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.dst_index as u64))?;
-            let tx_dst_addr_wit =
-                alloc_point(&mut *cs, filled, trans.tx.dst_pub_key.0.decompress())?;
-            let tx_amount_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.amount))?;
-            let tx_fee_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.fee))?;
-            let tx_hash_wit = poseidon::groth16::poseidon(
-                &mut *cs,
-                &[
-                    tx_nonce_wit.clone(),
-                    tx_src_index_wit.clone(),
-                    tx_dst_index_wit.clone(),
-                    tx_amount_wit.clone(),
-                    tx_fee_wit.clone(),
-                ],
-            )?;
-            let tx_sig_r_wit = alloc_point(&mut *cs, filled, trans.tx.sig.r)?;
-            let tx_sig_s_wit = alloc_num(&mut *cs, filled, trans.tx.sig.s)?;
-
-            let new_src_nonce_wit =
-                alloc_num(&mut *cs, filled, ZkScalar::from(trans.src_before.nonce + 1))?;
-            cs.enforce(
-                || "",
-                |lc| lc + src_nonce_wit.get_variable() + CS::one(),
-                |lc| lc + CS::one(),
-                |lc| lc + new_src_nonce_wit.get_variable(),
-            );
-            let new_src_balance_wit = alloc_num(
-                &mut *cs,
-                filled,
-                ZkScalar::from(trans.src_before.balance - trans.tx.amount - trans.tx.fee),
-            )?;
-            cs.enforce(
-                || "",
-                |lc| {
-                    lc + src_balance_wit.get_variable()
-                        - tx_amount_wit.get_variable()
-                        - tx_fee_wit.get_variable()
-                },
-                |lc| lc + CS::one(),
-                |lc| lc + new_src_balance_wit.get_variable(),
-            );
-            let new_src_hash_wit = poseidon::groth16::poseidon(
-                &mut *cs,
-                &[
-                    new_src_nonce_wit,
-                    src_addr_wit.x.clone(),
-                    src_addr_wit.y.clone(),
-                    new_src_balance_wit,
-                ],
-            )?;
-
-            let middle_root_wit = merkle::groth16::calc_root_poseidon4(
-                &mut *cs,
-                tx_src_index_wit.clone(),
-                new_src_hash_wit,
-                src_proof_wits.clone(),
-            )?;
+        let claimed_next_state_wit = alloc_num(&mut *cs, filled, self.next_state)?;
+        claimed_next_state_wit.inputize(cs.namespace(|| "next_state"))?;
 
-            let dst_nonce_wit =
-                alloc_num(&mut *cs, filled, ZkScalar::from(trans.dst_before.nonce))?;
-            let dst_addr_wit = alloc_point(&mut *cs, filled, trans.dst_before.address)?;
-            let dst_balance_wit =
-                alloc_num(&mut *cs, filled, ZkScalar::from(trans.dst_before.balance))?;
-            let dst_hash_wit = poseidon::groth16::poseidon(
-                &mut *cs,
-                &[
-                    dst_nonce_wit.clone(),
-                    dst_addr_wit.x.clone(),
-                    dst_addr_wit.y.clone(),
-                    dst_balance_wit.clone(),
-                ],
-            )?;
-            let mut dst_proof_wits = Vec::new();
-            for b in trans.dst_proof.0.clone() {
-                dst_proof_wits.push([
-                    alloc_num(&mut *cs, filled, b[0])?,
-                    alloc_num(&mut *cs, filled, b[1])?,
-                    alloc_num(&mut *cs, filled, b[2])?,
-                ]);
-            }
+        cs.enforce(
+            || "",
+            |lc| lc + state_wit.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + claimed_next_state_wit.get_variable(),
+        );
 
-            let new_dst_balance_wit = alloc_num(
-                &mut *cs,
-                filled,
-                ZkScalar::from(trans.dst_before.balance + trans.tx.amount),
-            )?;
-            cs.enforce(
-                || "",
-                |lc| lc + dst_balance_wit.get_variable() + tx_amount_wit.get_variable(),
-                |lc| lc + CS::one(),
-                |lc| lc + new_dst_balance_wit.get_variable(),
-            );
-
-            // enforce dst_addr_wit == tx_dst_addr_wit or zero!
-            cs.enforce(
-                || "",
-                |lc| lc + dst_addr_wit.x.get_variable(),
-                |lc| lc + dst_addr_wit.x.get_variable() - tx_dst_addr_wit.x.get_variable(),
-                |lc| lc,
-            );
-            cs.enforce(
-                || "",
-                |lc| lc + dst_addr_wit.y.get_variable(),
-                |lc| lc + dst_addr_wit.y.get_variable() - tx_dst_addr_wit.y.get_variable(),
-                |lc| lc,
-            );
-
-            let new_dst_hash_wit = poseidon::groth16::poseidon(
-                &mut *cs,
-                &[
-                    dst_nonce_wit,
-                    tx_dst_addr_wit.x,
-                    tx_dst_addr_wit.y,
-                    new_dst_balance_wit,
-                ],
-            )?;
+        Ok(())
+    }
+}
 
-            merkle::groth16::check_proof_poseidon4(
-                &mut *cs,
-                enabled_wit.clone(),
-                tx_dst_index_wit.clone(),
-                dst_hash_wit,
-                dst_proof_wits.clone(),
-                middle_root_wit,
-            )?;
-            merkle::groth16::check_proof_poseidon4(
-                &mut *cs,
-                enabled_wit.clone(),
-                tx_src_index_wit,
-                src_hash_wit,
-                src_proof_wits,
-                state_wit.clone(),
-            )?;
+impl Circuit<BellmanFr> for DepositWithdrawCircuit {
+    fn synthesize<CS: ConstraintSystem<BellmanFr>>(
+        self,
+        cs: &mut CS,
+    ) -> Result<(), SynthesisError> {
+        let filled = self.filled;
 
-            // WARN: MIGHT OVERFLOW!
-            let tx_balance_plus_fee = alloc_num(
-                &mut *cs,
-                filled,
-                ZkScalar::from(trans.tx.amount + trans.tx.fee),
-            )?;
-            cs.enforce(
-                || "",
-                |lc| lc + tx_amount_wit.get_variable() + tx_fee_wit.get_variable(),
-                |lc| lc + CS::one(),
-                |lc| lc + tx_balance_plus_fee.get_variable(),
-            );
-            common::groth16::lte(&mut *cs, tx_balance_plus_fee, src_balance_wit)?;
-
-            cs.enforce(
-                || "",
-                |lc| lc + tx_nonce_wit.get_variable(),
-                |lc| lc + CS::one(),
-                |lc| lc + src_nonce_wit.get_variable(),
-            );
-
-            eddsa::groth16::verify_eddsa(
-                &mut *cs,
-                enabled_wit.clone(),
-                src_addr_wit,
-                tx_hash_wit,
-                tx_sig_r_wit,
-                tx_sig_s_wit,
-            )?;
+        let mut state_wit = alloc_num(&mut *cs, filled, self.state)?;
+        state_wit.inputize(cs.namespace(|| "state"))?;
 
-            let next_state_wit = merkle::groth16::calc_root_poseidon4(
-                &mut *cs,
-                tx_dst_index_wit,
-                new_dst_hash_wit,
-                dst_proof_wits,
-            )?;
+        let aux_wit = alloc_num(&mut *cs, filled, self.aux_data)?;
+        aux_wit.inputize(cs.namespace(|| "aux_data"))?;
+        cs.enforce(
+            || "",
+            |lc| lc + aux_wit.get_variable(),
+            |lc| lc + CS::one(),
+            |lc| lc + aux_wit.get_variable(),
+        );
 
-            state_wit = AllocatedNum::conditionally_reverse(
-                &mut *cs,
-                &state_wit,
-                &next_state_wit,
-                &Boolean::Is(enabled_wit),
-            )?
-            .0;
+        for (i, trans) in self.transitions.0.iter().enumerate() {
+            let cs = &mut cs.namespace(|| format!("transition {}", i));
+            let enabled_wit = AllocatedBit::alloc(&mut *cs, filled.then(|| trans.enabled))?;
+            state_wit = deposit_withdraw(&mut *cs, filled, enabled_wit, &state_wit, trans)?;
         }
 
         let claimed_next_state_wit = alloc_num(&mut *cs, filled, self.next_state)?;
-        claimed_next_state_wit.inputize(&mut *cs)?;
+        claimed_next_state_wit.inputize(cs.namespace(|| "next_state"))?;
 
         cs.enforce(
             || "",
@@ -274,7 +826,7 @@ impl Circuit<BellmanFr> for UpdateCircuit {
     }
 }
 
-impl Circuit<BellmanFr> for DepositWithdrawCircuit {
+impl Circuit<BellmanFr> for MpnCircuit {
     fn synthesize<CS: ConstraintSystem<BellmanFr>>(
         self,
         cs: &mut CS,
@@ -282,10 +834,10 @@ impl Circuit<BellmanFr> for DepositWithdrawCircuit {
         let filled = self.filled;
 
         let mut state_wit = alloc_num(&mut *cs, filled, self.state)?;
-        state_wit.inputize(&mut *cs)?;
+        state_wit.inputize(cs.namespace(|| "state"))?;
 
         let aux_wit = alloc_num(&mut *cs, filled, self.aux_data)?;
-        aux_wit.inputize(&mut *cs)?;
+        aux_wit.inputize(cs.namespace(|| "aux_data"))?;
         cs.enforce(
             || "",
             |lc| lc + aux_wit.get_variable(),
@@ -293,97 +845,46 @@ impl Circuit<BellmanFr> for DepositWithdrawCircuit {
             |lc| lc + aux_wit.get_variable(),
         );
 
-        for trans in self.transitions.0.iter() {
-            let enabled_wit = AllocatedBit::alloc(&mut *cs, filled.then(|| trans.enabled))?;
+        for (i, trans) in self.transitions.0.iter().enumerate() {
+            let cs = &mut cs.namespace(|| format!("transition {}", i));
+            let enabled_wit = AllocatedBit::alloc(&mut *cs, filled.then(|| trans.enabled()))?;
+            let is_transfer_wit =
+                AllocatedBit::alloc(&mut *cs, filled.then(|| trans.is_transfer()))?;
 
-            let src_nonce_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.before.nonce))?;
-            let src_addr_wit = alloc_point(&mut *cs, filled, trans.before.address)?;
-            let src_balance_wit =
-                alloc_num(&mut *cs, filled, ZkScalar::from(trans.before.balance))?;
-            let src_hash_wit = poseidon::groth16::poseidon(
-                &mut *cs,
-                &[
-                    src_nonce_wit.clone(),
-                    src_addr_wit.x.clone(),
-                    src_addr_wit.y.clone(),
-                    src_balance_wit.clone(),
-                ],
-            )?;
-
-            let mut proof_wits = Vec::new();
-            for b in trans.proof.0.clone() {
-                proof_wits.push([
-                    alloc_num(&mut *cs, filled, b[0])?,
-                    alloc_num(&mut *cs, filled, b[1])?,
-                    alloc_num(&mut *cs, filled, b[2])?,
-                ]);
-            }
-
-            let tx_index_wit = alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.index as u64))?;
-            let tx_pub_key_wit = alloc_point(&mut *cs, filled, trans.tx.pub_key.0.decompress())?;
-            let tx_amount_wit =
-                alloc_num(&mut *cs, filled, ZkScalar::from(trans.tx.amount as u64))?;
-
-            // enforce src_addr_wit == tx_pub_key_wit or zero!
-            cs.enforce(
-                || "",
-                |lc| lc + src_addr_wit.x.get_variable(),
-                |lc| lc + src_addr_wit.x.get_variable() - tx_pub_key_wit.x.get_variable(),
-                |lc| lc,
-            );
-            cs.enforce(
-                || "",
-                |lc| lc + src_addr_wit.y.get_variable(),
-                |lc| lc + src_addr_wit.y.get_variable() - tx_pub_key_wit.y.get_variable(),
-                |lc| lc,
-            );
-
-            merkle::groth16::check_proof_poseidon4(
-                &mut *cs,
-                enabled_wit.clone(),
-                tx_index_wit.clone(),
-                src_hash_wit,
-                proof_wits.clone(),
-                state_wit.clone(),
-            )?;
+            let transfer_enabled_wit = AllocatedBit::and(&mut *cs, &enabled_wit, &is_transfer_wit)?;
 
-            let balance_bits = common::groth16::to_bits(&mut *cs, src_balance_wit, 64)?;
-            let amount_bits = common::groth16::to_bits(&mut *cs, tx_amount_wit, 64)?;
-            let balance_amount_sum =
-                common::groth16::sum_bits(&mut *cs, balance_bits, amount_bits)?;
-            let balance_amount_sum_bits =
-                common::groth16::to_bits(&mut *cs, balance_amount_sum, 65)?;
-            let new_balance_wit =
-                common::groth16::from_bits(&mut *cs, balance_amount_sum_bits[..64].to_vec())?;
-
-            let new_hash_wit = poseidon::groth16::poseidon(
+            // Every slot pays for a single account update, plus the debit of
+            // the source when it is a transfer. The credit of a transfer is
+            // the same update as a deposit.
+            let (debit_trans, credit_trans) = trans.split();
+            let (middle_state_wit, debit_wits) = token_debit(
                 &mut *cs,
-                &[
-                    src_nonce_wit,
-                    tx_pub_key_wit.x.clone(),
-                    tx_pub_key_wit.y.clone(),
-                    new_balance_wit,
-                ],
+                filled,
+                transfer_enabled_wit.clone(),
+                &state_wit,
+                &debit_trans,
             )?;
-
-            let next_state_wit = merkle::groth16::calc_root_poseidon4(
+            let (next_state_wit, credit_wits) = token_deposit_withdraw(
                 &mut *cs,
-                tx_index_wit,
-                new_hash_wit,
-                proof_wits,
+                filled,
+                enabled_wit,
+                &middle_state_wit,
+                &credit_trans,
             )?;
-
-            state_wit = AllocatedNum::conditionally_reverse(
-                &mut *cs,
-                &state_wit,
-                &next_state_wit,
-                &Boolean::Is(enabled_wit),
-            )?
-            .0;
+            for (signed, credited) in [
+                (&debit_wits.index, &credit_wits.index),
+                (&debit_wits.pub_key.x, &credit_wits.pub_key.x),
+                (&debit_wits.pub_key.y, &credit_wits.pub_key.y),
+                (&debit_wits.token_id, &credit_wits.token_id),
+                (&debit_wits.amount, &credit_wits.amount),
+            ] {
+                enforce_equal_if(&mut *cs, &transfer_enabled_wit, signed, credited);
+            }
+            state_wit = next_state_wit;
         }
 
         let claimed_next_state_wit = alloc_num(&mut *cs, filled, self.next_state)?;
-        claimed_next_state_wit.inputize(&mut *cs)?;
+        claimed_next_state_wit.inputize(cs.namespace(|| "next_state"))?;
 
         cs.enforce(
             || "",
@@ -1,6 +1,6 @@
 mod groth16;
 
-use crate::config::{BATCH_SIZE, LOG4_TREE_SIZE};
+use crate::config::{BATCH_SIZE, LOG4_TOKENS_TREE_SIZE, LOG4_TREE_SIZE};
 use crate::core;
 use bazuka::zk::{DepositWithdraw, ZeroTransaction, ZkScalar};
 use zeekit::merkle;
@@ -46,7 +46,7 @@ impl Default for TransitionBatch {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct UpdateCircuit {
     pub filled: bool,
     pub state: ZkScalar,                   // Public
@@ -85,7 +85,7 @@ impl Default for DepositWithdrawTransitionBatch {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct DepositWithdrawCircuit {
     pub filled: bool,
     pub state: ZkScalar,                                  // Public
@@ -93,3 +93,103 @@ pub struct DepositWithdrawCircuit {
     pub next_state: ZkScalar,                             // Public
     pub transitions: Box<DepositWithdrawTransitionBatch>, // Secret :)
 }
+
+#[derive(Debug, Clone, Default)]
+pub struct TokenTransition {
+    pub enabled: bool,
+    pub tx: core::TokenTransfer,
+    pub src_before: core::Account, // src_after can be derived
+    pub src_proof: merkle::Proof<LOG4_TREE_SIZE>,
+    pub src_token_proof: merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+    pub dst_before: core::Account, // dst_after can be derived
+    pub dst_proof: merkle::Proof<LOG4_TREE_SIZE>,
+    pub dst_token_proof: merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TokenDepositWithdrawTransition {
+    pub enabled: bool,
+    pub tx: core::TokenDepositWithdraw,
+    pub before: core::Account,
+    pub proof: merkle::Proof<LOG4_TREE_SIZE>,
+    pub token_proof: merkle::Proof<LOG4_TOKENS_TREE_SIZE>,
+}
+
+// A slot of the unified MPN circuit. Each slot credits one account, which is
+// the destination of a transfer, or the account of a deposit/withdraw. The
+// source of a transfer is debited before.
+#[derive(Debug, Clone)]
+pub enum MpnTransition {
+    Transfer(TokenTransition),
+    DepositWithdraw(TokenDepositWithdrawTransition),
+}
+
+impl MpnTransition {
+    pub fn enabled(&self) -> bool {
+        match self {
+            MpnTransition::Transfer(trans) => trans.enabled,
+            MpnTransition::DepositWithdraw(trans) => trans.enabled,
+        }
+    }
+    pub fn is_transfer(&self) -> bool {
+        matches!(self, MpnTransition::Transfer(_))
+    }
+    // The debit (Disabled for deposit/withdraws) and the credit of the slot
+    pub fn split(&self) -> (TokenTransition, TokenDepositWithdrawTransition) {
+        match self {
+            MpnTransition::Transfer(trans) => (
+                trans.clone(),
+                TokenDepositWithdrawTransition {
+                    enabled: trans.enabled,
+                    tx: core::TokenDepositWithdraw {
+                        index: trans.tx.dst_index,
+                        token_index: trans.tx.dst_token_index,
+                        pub_key: trans.tx.dst_pub_key.clone(),
+                        token_id: trans.tx.token_id,
+                        amount: trans.tx.amount as i64,
+                    },
+                    before: trans.dst_before.clone(),
+                    proof: trans.dst_proof.clone(),
+                    token_proof: trans.dst_token_proof.clone(),
+                },
+            ),
+            MpnTransition::DepositWithdraw(trans) => (TokenTransition::default(), trans.clone()),
+        }
+    }
+}
+
+impl Default for MpnTransition {
+    fn default() -> Self {
+        MpnTransition::DepositWithdraw(TokenDepositWithdrawTransition::default())
+    }
+}
+#[derive(Debug, Clone)]
+pub struct MpnTransitionBatch(pub [MpnTransition; BATCH_SIZE]);
+impl MpnTransitionBatch {
+    pub fn new(mut ts: Vec<MpnTransition>) -> Self {
+        while ts.len() < BATCH_SIZE {
+            ts.push(MpnTransition::default());
+        }
+        Self(ts.try_into().unwrap())
+    }
+}
+impl Default for MpnTransitionBatch {
+    fn default() -> Self {
+        Self(
+            (0..BATCH_SIZE)
+                .map(|_| MpnTransition::default())
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MpnCircuit {
+    pub filled: bool,
+    pub state: ZkScalar,                      // Public
+    pub aux_data: ZkScalar,                   // Public
+    pub next_state: ZkScalar,                 // Public
+    pub transitions: Box<MpnTransitionBatch>, // Secret :)
+}
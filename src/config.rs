@@ -1,3 +1,5 @@
 pub const LOG4_TREE_SIZE: u8 = 10;
 pub const LOG_BATCH_SIZE: usize = 2;
 pub const BATCH_SIZE: usize = 1 << LOG_BATCH_SIZE;
+pub const LOG4_TOKENS_TREE_SIZE: u8 = 1;
+pub const TOKENS_PER_ACCOUNT: usize = 1 << (2 * LOG4_TOKENS_TREE_SIZE);
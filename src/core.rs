@@ -1,8 +1,71 @@
-use bazuka::crypto::jubjub;
+use crate::config::TOKENS_PER_ACCOUNT;
+use bazuka::core::ZkHasher;
+use bazuka::crypto::{jubjub, ZkSignatureScheme};
+use bazuka::zk::ZkHasher as _;
+use bazuka::zk::ZkScalar;
 
+// The native coin is kept in `balance`, tokens issued on the chain in the
+// token-balance subtree.
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct Account {
     pub nonce: u64,
     pub address: jubjub::PointAffine,
     pub balance: u64,
+    pub tokens: [Token; TOKENS_PER_ACCOUNT],
+}
+
+// Empty token slots (Zero amount) may be taken by any token id.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Token {
+    pub token_id: ZkScalar,
+    pub amount: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TokenTransfer {
+    pub nonce: u64,
+    pub src_index: u32,
+    pub src_token_index: u32,
+    pub dst_index: u32,
+    pub dst_token_index: u32,
+    pub dst_pub_key: jubjub::PublicKey,
+    pub token_id: ZkScalar,
+    pub amount: u64,
+    pub fee: u64, // Paid in the same token
+    pub sig: jubjub::Signature,
+}
+
+impl TokenTransfer {
+    // Token slots are not signed, the circuit checks the token ids instead.
+    pub fn hash(&self) -> ZkScalar {
+        ZkHasher::hash(&[
+            ZkScalar::from(self.nonce),
+            ZkScalar::from(self.src_index as u64),
+            ZkScalar::from(self.dst_index as u64),
+            self.token_id,
+            ZkScalar::from(self.amount),
+            ZkScalar::from(self.fee),
+        ])
+    }
+    pub fn sign(&mut self, sk: jubjub::PrivateKey) {
+        self.sig = jubjub::JubJub::<ZkHasher>::sign(&sk, self.hash());
+    }
+    pub fn verify(&self, addr: jubjub::PublicKey) -> bool {
+        jubjub::JubJub::<ZkHasher>::verify(&addr, self.hash(), &self.sig)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TokenDepositWithdraw {
+    pub index: u32,
+    pub token_index: u32,
+    pub pub_key: jubjub::PublicKey,
+    pub token_id: ZkScalar,
+    pub amount: i64,
+}
+
+#[derive(Debug, Clone)]
+pub enum MpnOperation {
+    Transfer(TokenTransfer),
+    DepositWithdraw(TokenDepositWithdraw),
 }
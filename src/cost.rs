@@ -0,0 +1,158 @@
+use bellman::{Circuit, ConstraintSystem, Index, LinearCombination, SynthesisError, Variable};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use zeekit::BellmanFr;
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct Cost {
+    pub constraints: usize,
+    pub aux: usize,
+}
+
+// `by_namespace` holds the cost of each namespace path, including its nested
+// namespaces. `by_gadget` sums the cost of the namespaces with the same name
+// across transitions, e.g. `poseidon` counts every Poseidon hash of the circuit.
+// Each constraint is counted only toward its innermost gadget, so a gadget
+// excludes the gadgets nested in it. `num_inputs` includes the constant one,
+// the same as in `r1cs`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CostReport {
+    pub circuit: String,
+    pub total: Cost,
+    pub num_inputs: usize,
+    pub by_gadget: BTreeMap<String, Cost>,
+    pub by_namespace: BTreeMap<String, Cost>,
+}
+
+impl CostReport {
+    pub fn print(&self) {
+        println!(
+            "{}: {} constraints, {} aux variables, {} inputs",
+            self.circuit, self.total.constraints, self.total.aux, self.num_inputs
+        );
+        println!("  Per gadget:");
+        for (name, cost) in self.by_gadget.iter() {
+            println!(
+                "    {:<40} {:>10} constraints {:>10} aux",
+                name, cost.constraints, cost.aux
+            );
+        }
+        println!("  Per namespace:");
+        for (name, cost) in self.by_namespace.iter() {
+            println!(
+                "    {:<40} {:>10} constraints {:>10} aux",
+                name, cost.constraints, cost.aux
+            );
+        }
+    }
+}
+
+// Transition namespaces are reported per namespace, not per gadget
+fn is_transition(name: &str) -> bool {
+    name.starts_with("transition ")
+}
+
+// Counts the constraints and auxiliary variables allocated under each namespace
+pub struct CountingCs {
+    namespace: Vec<String>,
+    num_inputs: usize,
+    total: Cost,
+    by_gadget: BTreeMap<String, Cost>,
+    by_namespace: BTreeMap<String, Cost>,
+}
+
+impl Default for CountingCs {
+    fn default() -> Self {
+        Self {
+            namespace: Vec::new(),
+            num_inputs: 1, // The constant one
+            total: Cost::default(),
+            by_gadget: BTreeMap::new(),
+            by_namespace: BTreeMap::new(),
+        }
+    }
+}
+
+impl CountingCs {
+    fn count<F: Fn(&mut Cost)>(&mut self, f: F) {
+        f(&mut self.total);
+        for i in 1..=self.namespace.len() {
+            f(self
+                .by_namespace
+                .entry(self.namespace[..i].join("/"))
+                .or_default());
+        }
+        if let Some(gadget) = self.namespace.iter().rev().find(|n| !is_transition(n)) {
+            f(self.by_gadget.entry(gadget.clone()).or_default());
+        }
+    }
+
+    pub fn into_report(self, circuit: &str) -> CostReport {
+        CostReport {
+            circuit: circuit.into(),
+            total: self.total,
+            num_inputs: self.num_inputs,
+            by_gadget: self.by_gadget,
+            by_namespace: self.by_namespace,
+        }
+    }
+}
+
+impl ConstraintSystem<BellmanFr> for CountingCs {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(&mut self, _annotation: A, _f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        let index = self.total.aux;
+        self.count(|c| c.aux += 1);
+        Ok(Variable::new_unchecked(Index::Aux(index)))
+    }
+
+    fn alloc_input<F, A, AR>(&mut self, _annotation: A, _f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        let index = self.num_inputs;
+        self.num_inputs += 1;
+        Ok(Variable::new_unchecked(Index::Input(index)))
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(&mut self, _annotation: A, _a: LA, _b: LB, _c: LC)
+    where
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+        LA: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LB: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LC: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+    {
+        self.count(|c| c.constraints += 1);
+    }
+
+    fn push_namespace<NR, N>(&mut self, name_fn: N)
+    where
+        NR: Into<String>,
+        N: FnOnce() -> NR,
+    {
+        self.namespace.push(name_fn().into());
+    }
+
+    fn pop_namespace(&mut self) {
+        self.namespace.pop();
+    }
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
+
+pub fn report<C: Circuit<BellmanFr> + Default>(name: &str) -> Result<CostReport, SynthesisError> {
+    let mut cs = CountingCs::default();
+    C::default().synthesize(&mut cs)?;
+    Ok(cs.into_report(name))
+}
@@ -0,0 +1,256 @@
+use crate::backend::ProvingBackend;
+use crate::bank::{Bank, BankError};
+use crate::config::BATCH_SIZE;
+use crate::core::{MpnOperation, TokenDepositWithdraw, TokenTransfer};
+use crate::query::{AccountQuery, QueryError};
+use crate::state;
+use bazuka::core::ZkHasher;
+use bazuka::crypto::{jubjub, ZkSignatureScheme};
+use bazuka::db::KvStore;
+use bazuka::zk::{DepositWithdraw, ZeroTransaction, ZkScalar};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use thiserror::Error;
+use tracing::{debug, info};
+
+// Names of the first synthetic users, the rest are called user-<index>
+const NAMES: [&str; 3] = ["alice", "bob", "charlie"];
+const MAX_DEPOSIT: u64 = 1000;
+const MAX_FEE: u64 = 2;
+// Token operations run on a separate state of the latest model version, with a
+// single token kept in the first slot of each account
+const TOKEN_ID: u64 = 1;
+const TOKEN_INDEX: u32 = 0;
+
+#[derive(Error, Debug)]
+pub enum DevnetError {
+    #[error("bank error: {0:?}")]
+    BankError(BankError),
+    #[error("query error: {0}")]
+    QueryError(#[from] QueryError),
+    #[error("account {0} has nonce {1} and balance {2}, expected {3} and {4}")]
+    AccountMismatch(u32, u64, u64, u64, u64),
+    #[error("account {0} has nonce {1} and token balance {2}, expected {3} and {4}")]
+    TokenAccountMismatch(u32, u64, u64, u64, u64),
+}
+
+// A synthetic user, with the account state we expect after each batch. User i
+// owns the account at index i.
+struct User {
+    name: String,
+    keys: (jubjub::PublicKey, jubjub::PrivateKey),
+    nonce: u64,
+    balance: u64,
+    token_nonce: u64,
+    token_balance: u64,
+}
+
+fn random_deposits(rng: &mut StdRng, users: &mut [User]) -> Vec<DepositWithdraw> {
+    (0..rng.gen_range(1..=BATCH_SIZE))
+        .map(|_| {
+            let i = rng.gen_range(0..users.len());
+            let amount = rng.gen_range(1..=MAX_DEPOSIT);
+            users[i].balance += amount;
+            DepositWithdraw {
+                index: i as u32,
+                pub_key: users[i].keys.0.clone(),
+                amount: amount as i64,
+            }
+        })
+        .collect()
+}
+
+fn random_transfers(rng: &mut StdRng, users: &mut [User]) -> Vec<ZeroTransaction> {
+    let mut txs = Vec::new();
+    for _ in 0..rng.gen_range(1..=BATCH_SIZE) {
+        let src = rng.gen_range(0..users.len());
+        let dst = rng.gen_range(0..users.len());
+        let fee = rng.gen_range(0..=MAX_FEE);
+        if src == dst || users[src].balance <= fee {
+            continue;
+        }
+        let amount = rng.gen_range(1..=users[src].balance - fee);
+        let mut tx = ZeroTransaction {
+            nonce: users[src].nonce,
+            src_index: src as u32,
+            dst_index: dst as u32,
+            dst_pub_key: users[dst].keys.0.clone(),
+            amount,
+            fee,
+            sig: jubjub::Signature::default(),
+        };
+        tx.sign(users[src].keys.1.clone());
+        users[src].nonce += 1;
+        users[src].balance -= amount + fee;
+        users[dst].balance += amount;
+        txs.push(tx);
+    }
+    txs
+}
+
+// Token deposits and transfers, in the order they are processed
+fn random_token_ops(rng: &mut StdRng, users: &mut [User]) -> Vec<MpnOperation> {
+    let mut ops = Vec::new();
+    for _ in 0..rng.gen_range(1..=BATCH_SIZE) {
+        let src = rng.gen_range(0..users.len());
+        let fee = rng.gen_range(0..=MAX_FEE);
+        if rng.gen_bool(0.5) || users[src].token_balance <= fee {
+            let amount = rng.gen_range(1..=MAX_DEPOSIT);
+            users[src].token_balance += amount;
+            ops.push(MpnOperation::DepositWithdraw(TokenDepositWithdraw {
+                index: src as u32,
+                token_index: TOKEN_INDEX,
+                pub_key: users[src].keys.0.clone(),
+                token_id: ZkScalar::from(TOKEN_ID),
+                amount: amount as i64,
+            }));
+            continue;
+        }
+        let dst = rng.gen_range(0..users.len());
+        if src == dst {
+            continue;
+        }
+        let amount = rng.gen_range(1..=users[src].token_balance - fee);
+        let mut tx = TokenTransfer {
+            nonce: users[src].token_nonce,
+            src_index: src as u32,
+            src_token_index: TOKEN_INDEX,
+            dst_index: dst as u32,
+            dst_token_index: TOKEN_INDEX,
+            dst_pub_key: users[dst].keys.0.clone(),
+            token_id: ZkScalar::from(TOKEN_ID),
+            amount,
+            fee,
+            sig: jubjub::Signature::default(),
+        };
+        tx.sign(users[src].keys.1.clone());
+        users[src].token_nonce += 1;
+        users[src].token_balance -= amount + fee;
+        users[dst].token_balance += amount;
+        ops.push(MpnOperation::Transfer(tx));
+    }
+    ops
+}
+
+fn check_accounts<K: KvStore>(db: &K, users: &[User]) -> Result<(), DevnetError> {
+    let query = AccountQuery::new(db, state::CHAIN_STATE_MODEL_VERSION);
+    for (i, user) in users.iter().enumerate() {
+        let acc = query.get(i as u32)?;
+        if acc.nonce != user.nonce || acc.balance != user.balance {
+            return Err(DevnetError::AccountMismatch(
+                i as u32,
+                acc.nonce,
+                acc.balance,
+                user.nonce,
+                user.balance,
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_token_accounts<K: KvStore>(db: &K, users: &[User]) -> Result<(), DevnetError> {
+    let query = AccountQuery::new(db, state::STATE_MODEL_VERSION);
+    for (i, user) in users.iter().enumerate() {
+        let acc = query.get(i as u32)?;
+        let amount = acc.tokens[TOKEN_INDEX as usize].amount;
+        if acc.nonce != user.token_nonce || amount != user.token_balance {
+            return Err(DevnetError::TokenAccountMismatch(
+                i as u32,
+                acc.nonce,
+                amount,
+                user.token_nonce,
+                user.token_balance,
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Runs random deposit and transfer batches on an in-memory MPN state, proving
+// and verifying every batch and checking the resulting accounts. Each round
+// also processes token deposits and transfers in a single batch, on a
+// separate state of the latest model version. Runs forever when `rounds` is
+// not given.
+pub fn run<B: ProvingBackend>(
+    bank: &Bank<B>,
+    users: u32,
+    rounds: Option<u64>,
+    seed: u64,
+) -> Result<(), DevnetError> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut users = (0..std::cmp::max(users as usize, NAMES.len()))
+        .map(|i| {
+            let name = NAMES
+                .get(i)
+                .map(|n| n.to_string())
+                .unwrap_or_else(|| format!("user-{}", i));
+            User {
+                keys: jubjub::JubJub::<ZkHasher>::generate_keys(name.as_bytes()),
+                name,
+                nonce: 0,
+                balance: 0,
+                token_nonce: 0,
+                token_balance: 0,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut db = state::genesis_db(state::CHAIN_STATE_MODEL_VERSION);
+    let mut token_db = state::genesis_db(state::STATE_MODEL_VERSION);
+    let mut round = 0;
+    while rounds.map(|r| round < r).unwrap_or(true) {
+        let deposits = random_deposits(&mut rng, &mut users);
+        let num_deposits = deposits.len();
+        let batch = bank
+            .prepare_deposit_withdraw(&db, deposits)
+            .map_err(DevnetError::BankError)?;
+        let ops = batch.ops.clone();
+        bank.prove_deposit_withdraw(batch)
+            .map_err(DevnetError::BankError)?;
+        db.update(&ops).unwrap();
+
+        let txs = random_transfers(&mut rng, &mut users);
+        let num_transfers = txs.len();
+        if !txs.is_empty() {
+            let batch = bank
+                .prepare_change_state(&db, txs)
+                .map_err(DevnetError::BankError)?;
+            let ops = batch.ops.clone();
+            bank.prove_change_state(batch)
+                .map_err(DevnetError::BankError)?;
+            db.update(&ops).unwrap();
+        }
+
+        let token_ops = random_token_ops(&mut rng, &mut users);
+        let num_token_ops = token_ops.len();
+        if !token_ops.is_empty() {
+            let batch = bank
+                .prepare_process(&token_db, token_ops)
+                .map_err(DevnetError::BankError)?;
+            let ops = batch.ops.clone();
+            bank.prove_process(batch).map_err(DevnetError::BankError)?;
+            token_db.update(&ops).unwrap();
+        }
+
+        check_accounts(&db, &users)?;
+        check_token_accounts(&token_db, &users)?;
+        info!(
+            round,
+            deposits = num_deposits,
+            transfers = num_transfers,
+            token_ops = num_token_ops,
+            root = ?bank.root(&db).state_hash,
+            "Round finished"
+        );
+        for user in users.iter().take(NAMES.len()) {
+            debug!(
+                user = %user.name,
+                balance = user.balance,
+                token_balance = user.token_balance
+            );
+        }
+        round += 1;
+    }
+    Ok(())
+}
@@ -0,0 +1,703 @@
+use crate::backend::{Groth16Backend, ProvingBackend};
+use crate::bank::{Bank, BankError, PreparedBatch, StateOverlay};
+use crate::metrics;
+use crate::receipts::{item_id, receipt_id, ReceiptStatus, ReceiptStore};
+use crate::rpc::TxQueue;
+use crate::state::CHAIN_STATE_MODEL_VERSION;
+use crate::{circuits, config, node, prover, witness, ZoroError};
+use bazuka::client::PeerAddress;
+use bazuka::config::blockchain::MPN_CONTRACT_ID;
+use bazuka::core::{ContractPayment, ContractUpdate, PaymentDirection};
+use bazuka::db::{KvStore, ReadOnlyLevelDbKvStore, WriteOp};
+use bazuka::zk::{DepositWithdraw, ZeroTransaction, ZkCompressedState, ZkDeltaPairs, ZkScalar};
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+use tracing::{debug, info, info_span, warn, Span};
+
+type Proof = <Groth16Backend as ProvingBackend>::Proof;
+type ProofResult = Result<(ZkDeltaPairs, ZkCompressedState, Proof), BankError>;
+
+fn node_error(method: &str, e: ZoroError) -> ZoroError {
+    metrics::NODE_ERRORS.with_label_values(&[method]).inc();
+    e
+}
+
+fn elapsed_ms(start: Instant) -> u64 {
+    start.elapsed().as_millis() as u64
+}
+
+// Span of a batch, from building its witness until it's submitted
+fn batch_span(kind: &'static str, txs: usize) -> Span {
+    info_span!(
+        "batch",
+        kind,
+        txs,
+        id = tracing::field::Empty,
+        state = tracing::field::Empty,
+        next_state = tracing::field::Empty,
+    )
+}
+
+pub fn to_deposit_withdraw(payment: &ContractPayment) -> DepositWithdraw {
+    DepositWithdraw {
+        index: payment.zk_address_index,
+        pub_key: payment.zk_address.clone(),
+        amount: match payment.direction {
+            PaymentDirection::Deposit(_) => payment.amount as i64,
+            PaymentDirection::Withdraw(_) => -(payment.amount as i64),
+        },
+    }
+}
+
+// A batch submitted to the node, which is not applied on the chain yet
+struct InFlight {
+    state: ZkScalar,
+    next_state: ZkScalar,
+    ops: Vec<WriteOp>,
+    items: HashSet<Vec<u8>>,
+    batch: Option<u64>,
+}
+
+// Batches with their witnesses built, waiting for the previous stage to be proven
+#[derive(Default)]
+struct PreparedStage {
+    deposit_withdraw: Option<(
+        Vec<ContractPayment>,
+        PreparedBatch<circuits::DepositWithdrawCircuit>,
+        Span,
+    )>,
+    update: Option<(
+        Vec<ZeroTransaction>,
+        PreparedBatch<circuits::UpdateCircuit>,
+        Span,
+    )>,
+}
+
+impl PreparedStage {
+    fn is_empty(&self) -> bool {
+        self.deposit_withdraw.is_none() && self.update.is_none()
+    }
+}
+
+// A batch whose proof is being generated on a separate thread
+struct ProvingBatch<T> {
+    items: Vec<T>,
+    state: ZkScalar,
+    ops: Vec<WriteOp>,
+    handle: JoinHandle<ProofResult>,
+    span: Span,
+}
+
+impl<T: Serialize> ProvingBatch<T> {
+    fn receipt_ids(&self) -> Vec<String> {
+        self.items.iter().map(receipt_id).collect()
+    }
+
+    fn in_flight(&self, next_state: ZkScalar) -> InFlight {
+        InFlight {
+            state: self.state,
+            next_state,
+            ops: self.ops.clone(),
+            items: self.items.iter().map(item_id).collect(),
+            batch: None,
+        }
+    }
+}
+
+// Deposit/withdraw and transfer batches, proven concurrently
+struct ProvingStage {
+    deposit_withdraw: Option<ProvingBatch<ContractPayment>>,
+    update: Option<ProvingBatch<ZeroTransaction>>,
+}
+
+impl ProvingStage {
+    fn ops(&self) -> Vec<&[WriteOp]> {
+        let mut ops = Vec::new();
+        if let Some(b) = &self.deposit_withdraw {
+            ops.push(&b.ops[..]);
+        }
+        if let Some(b) = &self.update {
+            ops.push(&b.ops[..]);
+        }
+        ops
+    }
+
+    fn items(&self) -> HashSet<Vec<u8>> {
+        let mut items = HashSet::new();
+        if let Some(b) = &self.deposit_withdraw {
+            items.extend(b.items.iter().map(item_id));
+        }
+        if let Some(b) = &self.update {
+            items.extend(b.items.iter().map(item_id));
+        }
+        items
+    }
+}
+
+// Builds the witness of the next stage on the predicted state of the previous
+// stage, while the previous stage is being proven.
+pub struct Executor {
+    bank: Arc<Bank<Groth16Backend>>,
+    provers: prover::ProverPool,
+    node: PeerAddress,
+    wallet: bazuka::wallet::Wallet,
+    queue: Arc<Mutex<TxQueue>>,
+    receipts: Arc<Mutex<ReceiptStore>>,
+    in_flight: VecDeque<InFlight>,
+    last_nonce: u32,
+    last_root: Option<ZkScalar>,
+    root_changed: Instant,
+}
+
+impl Executor {
+    pub fn new(
+        bank: Bank<Groth16Backend>,
+        provers: prover::ProverPool,
+        node: PeerAddress,
+        wallet: bazuka::wallet::Wallet,
+        queue: Arc<Mutex<TxQueue>>,
+        receipts: Arc<Mutex<ReceiptStore>>,
+    ) -> Self {
+        Self {
+            bank: Arc::new(bank),
+            provers,
+            node,
+            wallet,
+            queue,
+            receipts,
+            in_flight: VecDeque::new(),
+            last_nonce: 0,
+            last_root: None,
+            root_changed: Instant::now(),
+        }
+    }
+
+    pub fn run(mut self, db_shutter: ReadOnlyLevelDbKvStore) {
+        let mut proving: Option<ProvingStage> = None;
+        loop {
+            let db = db_shutter.snapshot();
+            let root = self.bank.root(&db).state_hash;
+            if self.last_root != Some(root) {
+                self.last_root = Some(root);
+                self.root_changed = Instant::now();
+            }
+            metrics::STATE_ROOT_AGE.set(self.root_changed.elapsed().as_secs() as i64);
+            self.sync(root);
+            // The receipts changed since the previous sync are written at once
+            if let Err(e) = self.receipts.lock().unwrap().flush() {
+                warn!(error = %e, "Cannot store receipts");
+            }
+            {
+                let queue = self.queue.lock().unwrap();
+                metrics::QUEUE_SIZE
+                    .with_label_values(&["deposit_withdraw"])
+                    .set(queue.deposit_withdraws.len() as i64);
+                metrics::QUEUE_SIZE
+                    .with_label_values(&["update"])
+                    .set(queue.transactions.len() as i64);
+            }
+
+            let mempool = match node::get_zero_mempool(self.node) {
+                Ok(mempool) => {
+                    metrics::MEMPOOL_SIZE
+                        .with_label_values(&["deposit_withdraw"])
+                        .set(
+                            mempool
+                                .deposit_withdraws
+                                .iter()
+                                .filter(|dw| dw.contract_id == *MPN_CONTRACT_ID)
+                                .count() as i64,
+                        );
+                    metrics::MEMPOOL_SIZE
+                        .with_label_values(&["update"])
+                        .set(mempool.updates.len() as i64);
+                    mempool
+                }
+                Err(e) => {
+                    let e = node_error("get_zero_mempool", e);
+                    warn!(error = %e, "Cannot get the mempool");
+                    std::thread::sleep(std::time::Duration::from_millis(1000));
+                    continue;
+                }
+            };
+
+            // Predict the state after the submitted and the currently proving
+            // batches, the same state the RPC checks transactions against
+            self.publish(&proving);
+            let predicted = self.queue.lock().unwrap().predict(&db);
+            let mut exclude = HashSet::new();
+            for f in self.in_flight.iter() {
+                exclude.extend(f.items.iter().cloned());
+            }
+            if let Some(stage) = &proving {
+                exclude.extend(stage.items());
+            }
+
+            let prepared = self.prepare(&predicted, mempool, &exclude);
+
+            if let Some(stage) = proving.take() {
+                // The prepared batches are built on top of the proving ones
+                if !self.submit(stage) {
+                    warn!("Prepared batches dropped, the previous stage was not submitted");
+                    continue;
+                }
+            }
+
+            if prepared.is_empty() {
+                self.publish(&proving);
+                debug!("No transactions");
+                std::thread::sleep(std::time::Duration::from_millis(1000));
+            } else {
+                proving = Some(self.launch(prepared));
+                self.publish(&proving);
+            }
+        }
+    }
+
+    // Shares the writes of the submitted and the currently proving batches
+    // with the RPC, in the order they were built on each other
+    fn publish(&self, proving: &Option<ProvingStage>) {
+        let mut pending = Vec::new();
+        for f in self.in_flight.iter() {
+            pending.extend(f.ops.iter().cloned());
+        }
+        if let Some(stage) = proving {
+            for ops in stage.ops() {
+                pending.extend(ops.iter().cloned());
+            }
+        }
+        self.queue.lock().unwrap().pending = pending;
+    }
+
+    // Forgets the batches which are applied on the chain. If the chain has
+    // diverged from what we have submitted, everything is rebuilt.
+    fn sync(&mut self, root: ZkScalar) {
+        if let Some(pos) = self.in_flight.iter().position(|f| f.next_state == root) {
+            let mut applied = HashSet::new();
+            info!(root = ?root, batches = pos + 1, "Batches applied");
+            for f in self.in_flight.drain(..=pos).collect::<Vec<_>>() {
+                self.set_status(&f, ReceiptStatus::Applied);
+                applied.extend(f.items);
+            }
+            self.queue.lock().unwrap().remove(&applied);
+        } else if let Some(first) = self.in_flight.front() {
+            if first.state != root {
+                warn!(
+                    root = ?root,
+                    batches = self.in_flight.len(),
+                    "Submitted batches were not applied"
+                );
+                for f in std::mem::take(&mut self.in_flight) {
+                    self.set_status(&f, ReceiptStatus::Dropped);
+                }
+            }
+        }
+    }
+
+    fn set_status(&self, in_flight: &InFlight, status: ReceiptStatus) {
+        if let Some(batch) = in_flight.batch {
+            self.receipts.lock().unwrap().set_status(batch, status);
+        }
+    }
+
+    // Records the receipts of a batch sent to the node
+    fn submitted(&self, mut in_flight: InFlight, ids: Vec<String>, l1_tx: String) -> InFlight {
+        in_flight.batch = Some(self.receipts.lock().unwrap().submitted(
+            ids,
+            in_flight.state,
+            in_flight.next_state,
+            l1_tx,
+        ));
+        in_flight
+    }
+
+    fn prepare<K: KvStore>(
+        &self,
+        db: &K,
+        mempool: bazuka::client::messages::GetZeroMempoolResponse,
+        exclude: &HashSet<Vec<u8>>,
+    ) -> PreparedStage {
+        let mut stage = PreparedStage::default();
+        let mut predicted = db.mirror();
+
+        // Transactions submitted through the RPC come first, the same
+        // transaction might also be in the mempool.
+        let (queued_dws, queued_txs) = {
+            let queue = self.queue.lock().unwrap();
+            (queue.deposit_withdraws.clone(), queue.transactions.clone())
+        };
+        let mut seen = exclude.clone();
+        let mut rejected = HashSet::new();
+
+        let contract_payments = {
+            let mut overlay = match StateOverlay::new(&predicted, CHAIN_STATE_MODEL_VERSION) {
+                Ok(overlay) => overlay,
+                Err(e) => {
+                    metrics::bank_error(&e);
+                    return stage;
+                }
+            };
+            select(
+                queued_dws,
+                mempool
+                    .deposit_withdraws
+                    .into_iter()
+                    .filter(|dw| dw.contract_id == *MPN_CONTRACT_ID)
+                    .collect(),
+                &mut seen,
+                &mut rejected,
+                |dw| overlay.apply_deposit_withdraw(&to_deposit_withdraw(dw)),
+            )
+        };
+        if !contract_payments.is_empty() {
+            let deposit_withdraws = contract_payments
+                .iter()
+                .map(to_deposit_withdraw)
+                .collect::<Vec<_>>();
+            let span = batch_span("deposit_withdraw", deposit_withdraws.len());
+            let _enter = span.enter();
+            debug!(deposit_withdraws = ?deposit_withdraws);
+            let start = Instant::now();
+            match self
+                .bank
+                .prepare_deposit_withdraw(&predicted, deposit_withdraws)
+            {
+                Ok(batch) => {
+                    span.record("state", tracing::field::debug(&batch.state));
+                    span.record(
+                        "next_state",
+                        tracing::field::debug(&batch.next_state.state_hash),
+                    );
+                    info!(witness_ms = elapsed_ms(start), "Witness built");
+                    predicted.update(&batch.ops).unwrap();
+                    drop(_enter);
+                    stage.deposit_withdraw = Some((contract_payments, batch, span));
+                }
+                Err(e) => {
+                    metrics::bank_error(&e);
+                    warn!(error = ?e, "Cannot prepare deposit/withdraws");
+                }
+            }
+        }
+
+        let txs = {
+            let mut overlay = match StateOverlay::new(&predicted, CHAIN_STATE_MODEL_VERSION) {
+                Ok(overlay) => overlay,
+                Err(e) => {
+                    metrics::bank_error(&e);
+                    return stage;
+                }
+            };
+            select(
+                queued_txs,
+                mempool.updates,
+                &mut seen,
+                &mut rejected,
+                |tx| overlay.apply_transfer(tx),
+            )
+        };
+        if !txs.is_empty() {
+            let span = batch_span("update", txs.len());
+            let _enter = span.enter();
+            let start = Instant::now();
+            match self.bank.prepare_change_state(&predicted, txs.clone()) {
+                Ok(batch) => {
+                    span.record("state", tracing::field::debug(&batch.state));
+                    span.record(
+                        "next_state",
+                        tracing::field::debug(&batch.next_state.state_hash),
+                    );
+                    info!(witness_ms = elapsed_ms(start), "Witness built");
+                    drop(_enter);
+                    stage.update = Some((txs, batch, span));
+                }
+                Err(e) => {
+                    metrics::bank_error(&e);
+                    warn!(error = ?e, "Cannot prepare transfers");
+                }
+            }
+        }
+
+        if !rejected.is_empty() {
+            self.queue.lock().unwrap().remove(&rejected);
+        }
+        stage
+    }
+
+    fn launch(&self, prepared: PreparedStage) -> ProvingStage {
+        ProvingStage {
+            deposit_withdraw: prepared.deposit_withdraw.map(|(items, batch, span)| {
+                let bank = Arc::clone(&self.bank);
+                let provers = self.provers.clone();
+                let thread_span = span.clone();
+                ProvingBatch {
+                    items,
+                    state: batch.state,
+                    ops: batch.ops.clone(),
+                    handle: std::thread::spawn(move || {
+                        let _enter = thread_span.enter();
+                        let start = Instant::now();
+                        let res = prove_batch(
+                            &provers,
+                            batch,
+                            witness::Witness::deposit_withdraw,
+                            |batch, proof| bank.verify_deposit_withdraw(batch, proof),
+                            |batch| bank.prove_deposit_withdraw(batch),
+                        );
+                        info!(
+                            proving_ms = elapsed_ms(start),
+                            remote = !provers.is_empty(),
+                            ok = res.is_ok(),
+                            "Proving finished"
+                        );
+                        metrics::PROVING_DURATION
+                            .with_label_values(&["deposit_withdraw"])
+                            .observe(start.elapsed().as_secs_f64());
+                        if res.is_ok() {
+                            metrics::BATCHES_PROVEN
+                                .with_label_values(&["deposit_withdraw"])
+                                .inc();
+                        }
+                        res
+                    }),
+                    span,
+                }
+            }),
+            update: prepared.update.map(|(items, batch, span)| {
+                let bank = Arc::clone(&self.bank);
+                let provers = self.provers.clone();
+                let thread_span = span.clone();
+                ProvingBatch {
+                    items,
+                    state: batch.state,
+                    ops: batch.ops.clone(),
+                    handle: std::thread::spawn(move || {
+                        let _enter = thread_span.enter();
+                        let start = Instant::now();
+                        let res = prove_batch(
+                            &provers,
+                            batch,
+                            witness::Witness::update,
+                            |batch, proof| bank.verify_change_state(batch, proof),
+                            |batch| bank.prove_change_state(batch),
+                        );
+                        info!(
+                            proving_ms = elapsed_ms(start),
+                            remote = !provers.is_empty(),
+                            ok = res.is_ok(),
+                            "Proving finished"
+                        );
+                        metrics::PROVING_DURATION
+                            .with_label_values(&["update"])
+                            .observe(start.elapsed().as_secs_f64());
+                        if res.is_ok() {
+                            metrics::BATCHES_PROVEN.with_label_values(&["update"]).inc();
+                        }
+                        res
+                    }),
+                    span,
+                }
+            }),
+        }
+    }
+
+    // Waits for the proofs of the stage and sends them to the node, in the same
+    // order they were built on each other. Returns false if any of the batches
+    // was not submitted.
+    fn submit(&mut self, stage: ProvingStage) -> bool {
+        let mut failed = false;
+
+        if let Some(batch) = stage.deposit_withdraw {
+            let span = batch.span.clone();
+            let _enter = span.enter();
+            let res = join(batch.handle);
+            match res {
+                Ok((delta, next_state, proof)) => {
+                    let in_flight = batch.in_flight(next_state.state_hash);
+                    let ids = batch.receipt_ids();
+                    let update = ContractUpdate::DepositWithdraw {
+                        deposit_withdraws: batch.items,
+                        next_state,
+                        proof: bazuka::zk::ZkProof::Groth16(Box::new(proof)),
+                    };
+                    let start = Instant::now();
+                    match self.send(update, delta) {
+                        Ok(l1_tx) => {
+                            info!(submit_ms = elapsed_ms(start), l1_tx = %l1_tx, "Batch submitted");
+                            metrics::BATCHES_SUBMITTED
+                                .with_label_values(&["deposit_withdraw"])
+                                .inc();
+                            let in_flight = self.submitted(in_flight, ids, l1_tx);
+                            if let Some(id) = in_flight.batch {
+                                span.record("id", id);
+                            }
+                            self.in_flight.push_back(in_flight);
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "Cannot submit deposit/withdraws");
+                            failed = true;
+                        }
+                    }
+                }
+                Err(e) => {
+                    metrics::bank_error(&e);
+                    warn!(error = ?e, "Cannot prove deposit/withdraws");
+                    failed = true;
+                }
+            }
+        }
+
+        if let Some(batch) = stage.update {
+            let span = batch.span.clone();
+            let _enter = span.enter();
+            let res = join(batch.handle);
+            // Transfers are built on top of the deposit/withdraws
+            if failed {
+                warn!("Skipped, the deposit/withdraws were not submitted");
+                return false;
+            }
+            match res {
+                Ok((delta, next_state, proof)) => {
+                    let in_flight = batch.in_flight(next_state.state_hash);
+                    let ids = batch.receipt_ids();
+                    let update = ContractUpdate::FunctionCall {
+                        function_id: 0,
+                        next_state,
+                        proof: bazuka::zk::ZkProof::Groth16(Box::new(proof)),
+                        fee: 0,
+                    };
+                    let start = Instant::now();
+                    match self.send(update, delta) {
+                        Ok(l1_tx) => {
+                            info!(submit_ms = elapsed_ms(start), l1_tx = %l1_tx, "Batch submitted");
+                            metrics::BATCHES_SUBMITTED
+                                .with_label_values(&["update"])
+                                .inc();
+                            let in_flight = self.submitted(in_flight, ids, l1_tx);
+                            if let Some(id) = in_flight.batch {
+                                span.record("id", id);
+                            }
+                            self.in_flight.push_back(in_flight);
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "Cannot submit transfers");
+                            failed = true;
+                        }
+                    }
+                }
+                Err(e) => {
+                    metrics::bank_error(&e);
+                    warn!(error = ?e, "Cannot prove transfers");
+                    failed = true;
+                }
+            }
+        }
+
+        !failed
+    }
+
+    // Returns the hash of the L1 transaction
+    fn send(&mut self, update: ContractUpdate, delta: ZkDeltaPairs) -> Result<String, ZoroError> {
+        // Previous updates might still be in the mempool
+        let nonce = std::cmp::max(
+            node::get_account(self.node, self.wallet.get_address())
+                .map_err(|e| node_error("get_account", e))?
+                .account
+                .nonce,
+            self.last_nonce,
+        ) + 1;
+
+        let mut tx = bazuka::core::Transaction {
+            src: self.wallet.get_address(),
+            nonce,
+            fee: 0,
+            data: bazuka::core::TransactionData::UpdateContract {
+                contract_id: *MPN_CONTRACT_ID,
+                updates: vec![update],
+            },
+            sig: bazuka::core::Signature::Unsigned,
+        };
+        self.wallet.sign(&mut tx);
+        let l1_tx = hex::encode(tx.hash());
+
+        node::transact(
+            self.node,
+            bazuka::core::TransactionAndDelta {
+                tx,
+                state_delta: Some(delta),
+            },
+        )
+        .map_err(|e| node_error("transact", e))?;
+        self.last_nonce = nonce;
+        Ok(l1_tx)
+    }
+}
+
+// Picks at most a batch of items which apply on top of each other, the queued
+// items first. Queued items which do not apply are added to `rejected`, to be
+// removed from the queue.
+fn select<T: Serialize, R>(
+    queued: Vec<T>,
+    mempool: Vec<T>,
+    seen: &mut HashSet<Vec<u8>>,
+    rejected: &mut HashSet<Vec<u8>>,
+    mut apply: impl FnMut(&T) -> Result<R, BankError>,
+) -> Vec<T> {
+    let mut selected = Vec::new();
+    let items = queued
+        .into_iter()
+        .map(|item| (true, item))
+        .chain(mempool.into_iter().map(|item| (false, item)));
+    for (is_queued, item) in items {
+        if selected.len() >= config::BATCH_SIZE {
+            break;
+        }
+        let id = item_id(&item);
+        if !seen.insert(id.clone()) {
+            continue;
+        }
+        match apply(&item) {
+            Ok(_) => selected.push(item),
+            Err(e) => {
+                metrics::bank_error(&e);
+                debug!(tx = %receipt_id(&item), error = ?e, queued = is_queued, "Item skipped");
+                if is_queued {
+                    rejected.insert(id);
+                }
+            }
+        }
+    }
+    selected
+}
+
+// A proving thread that panicked counts as a failed proof
+fn join(handle: JoinHandle<ProofResult>) -> ProofResult {
+    handle.join().unwrap_or_else(|_| {
+        warn!("Proving thread panicked");
+        Err(BankError::CannotProve)
+    })
+}
+
+// Proves the batch on the prover workers, falling back to proving it locally
+// when none of them returns a valid proof.
+fn prove_batch<C>(
+    provers: &prover::ProverPool,
+    batch: PreparedBatch<C>,
+    witness: impl FnOnce(&C) -> witness::Witness,
+    verify: impl FnOnce(&PreparedBatch<C>, &Proof) -> bool,
+    prove_locally: impl FnOnce(PreparedBatch<C>) -> ProofResult,
+) -> ProofResult {
+    if !provers.is_empty() {
+        match provers.prove::<Proof>(&witness(&batch.circuit)) {
+            Ok(proof) if verify(&batch, &proof) => {
+                return Ok((batch.delta, batch.next_state, proof));
+            }
+            Ok(_) => warn!("Invalid proof from the prover workers, proving locally"),
+            Err(e) => warn!(error = %e, "Remote proving failed, proving locally"),
+        }
+    }
+    prove_locally(batch)
+}
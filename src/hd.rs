@@ -0,0 +1,146 @@
+use bazuka::core::ZkHasher;
+use bazuka::crypto::{jubjub, ZkSignatureScheme};
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+use thiserror::Error;
+
+// Hierarchical deterministic derivation of jubjub keys, from a BIP39 seed. The
+// scheme is specific to zoro: it follows the structure of BIP32 hardened
+// derivation, but the keys are not compatible with BIP32 wallets.
+//
+// The master key is HMAC-SHA512("zoro jubjub seed", seed), split into a 32
+// byte key and a 32 byte chain code. The child at index i is
+// HMAC-SHA512(chain code, 0x00 || key || i) (Big-endian index), split the
+// same way. Only hardened children (i >= 2^31, written as i' in paths) are
+// supported, since jubjub public keys can't be derived without the private key
+// anyway. The jubjub keypair of a node is generated from its 32 byte key.
+//
+// MPN accounts use the path m/44'/<account>'.
+
+const MASTER_KEY: &[u8] = b"zoro jubjub seed";
+pub const HARDENED: u32 = 1 << 31;
+const PURPOSE: u32 = 44;
+
+#[derive(Error, Debug)]
+pub enum HdError {
+    #[error("invalid derivation path: {0}")]
+    InvalidPath(String),
+    #[error("index {0} is too large for a hardened child")]
+    InvalidIndex(u32),
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).unwrap();
+    mac.update(data);
+    let out = mac.finalize().into_bytes();
+    (out[..32].try_into().unwrap(), out[32..].try_into().unwrap())
+}
+
+#[derive(Clone)]
+pub struct ExtendedKey {
+    key: [u8; 32],
+    chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    pub fn master(seed: &[u8]) -> Self {
+        let (key, chain_code) = hmac_sha512(MASTER_KEY, seed);
+        Self { key, chain_code }
+    }
+
+    // `index` includes the hardened offset
+    pub fn child(&self, index: u32) -> Self {
+        let mut data = vec![0u8];
+        data.extend(self.key);
+        data.extend(index.to_be_bytes());
+        let (key, chain_code) = hmac_sha512(&self.chain_code, &data);
+        Self { key, chain_code }
+    }
+
+    pub fn derive(&self, path: &[u32]) -> Self {
+        path.iter().fold(self.clone(), |k, i| k.child(*i))
+    }
+
+    pub fn keys(&self) -> (jubjub::PublicKey, jubjub::PrivateKey) {
+        jubjub::JubJub::<ZkHasher>::generate_keys(&self.key)
+    }
+}
+
+fn hardened(index: u32) -> Result<u32, HdError> {
+    if index >= HARDENED {
+        return Err(HdError::InvalidIndex(index));
+    }
+    Ok(index + HARDENED)
+}
+
+pub fn account_path(account: u32) -> Result<Vec<u32>, HdError> {
+    Ok(vec![hardened(PURPOSE)?, hardened(account)?])
+}
+
+// Parses paths like m/44'/0', every index must be hardened
+pub fn parse_path(path: &str) -> Result<Vec<u32>, HdError> {
+    let mut parts = path.split('/');
+    if parts.next() != Some("m") {
+        return Err(HdError::InvalidPath(path.into()));
+    }
+    parts
+        .map(|p| {
+            p.strip_suffix('\'')
+                .and_then(|i| i.parse::<u32>().ok())
+                .ok_or_else(|| HdError::InvalidPath(path.into()))
+                .and_then(hardened)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: [u8; 64] = {
+        let mut seed = [0u8; 64];
+        let mut i = 0;
+        while i < 64 {
+            seed[i] = i as u8;
+            i += 1;
+        }
+        seed
+    };
+
+    fn assert_key(key: &ExtendedKey, expected_key: &str, expected_chain_code: &str) {
+        assert_eq!(hex::encode(key.key), expected_key);
+        assert_eq!(hex::encode(key.chain_code), expected_chain_code);
+    }
+
+    #[test]
+    fn test_derive() {
+        let master = ExtendedKey::master(&SEED);
+        assert_key(
+            &master,
+            "d8ab3caf188b68bcbf9d14f872d9c74909df5bc4f94c5717fa789a6146487740",
+            "d15dfaaacb49545e8da1c5257d40a873b9dafa3020aac4f2e14c6d95fad1609f",
+        );
+        assert_key(
+            &master.derive(&account_path(0).unwrap()),
+            "32d7465ca823b8f8ceaf320506a570454c3fd6d2dc6dff1a7aed78edf7c38b63",
+            "24a43fd70b4688cca55060c4465afb5d7718da87fbede8551a8e31b5711188c3",
+        );
+        assert_key(
+            &master.derive(&parse_path("m/44'/1'").unwrap()),
+            "d0349797f040f37c6319c533385e4c4e3f6134c768f5f074c2dbd19663b143e7",
+            "a60bed2cd689aff90c3e08cd7ed8b4764211b61282a947241df60e265c7a2a49",
+        );
+    }
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(
+            parse_path("m/44'/0'").unwrap(),
+            vec![44 + HARDENED, HARDENED]
+        );
+        assert_eq!(parse_path("m").unwrap(), Vec::<u32>::new());
+        for path in ["44'/0'", "m/44/0'", "m/44'/x'", "m/2147483648'", "m/44'/"] {
+            assert!(parse_path(path).is_err(), "{} accepted!", path);
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use crate::backend::ProvingBackend;
+use crate::bank::{Bank, BankError};
+use crate::config::BATCH_SIZE;
+use crate::query::{AccountQuery, QueryError};
+use crate::receipts::{receipt_id, Receipt, ReceiptStatus};
+use crate::state;
+use bazuka::crypto::jubjub;
+use bazuka::db::KvStore;
+use bazuka::zk::{DepositWithdraw, ZeroTransaction};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::warn;
+
+const INITIAL_BALANCE: u64 = 1_000_000;
+const MAX_AMOUNT: u64 = 100;
+const MAX_FEE: u64 = 2;
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Error, Debug)]
+pub enum LoadError {
+    #[error("bank error: {0:?}")]
+    BankError(BankError),
+    #[error("query error: {0}")]
+    QueryError(#[from] QueryError),
+    #[error("http error: {0}")]
+    HttpError(#[from] Box<ureq::Error>),
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("json error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("rpc error: {0}")]
+    RpcError(String),
+    #[error("not enough funded accounts")]
+    NotEnoughFunds,
+}
+
+// Latencies are from the time a transaction is generated (Or submitted, over
+// the RPC) until its batch is applied. The backlog is the number of
+// transactions waiting to be included in a proven batch. Over the RPC,
+// transactions whose batch is dropped are counted separately and no longer
+// waited for.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadReport {
+    pub transactions: usize,
+    pub duration_ms: f64,
+    pub tps: f64,
+    pub latency_p50_ms: f64,
+    pub latency_p90_ms: f64,
+    pub latency_p99_ms: f64,
+    pub latency_max_ms: f64,
+    pub max_backlog: usize,
+    pub dropped: usize,
+}
+
+impl LoadReport {
+    fn new(
+        mut latencies: Vec<f64>,
+        duration: Duration,
+        max_backlog: usize,
+        dropped: usize,
+    ) -> Self {
+        latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let percentile = |p: f64| {
+            if latencies.is_empty() {
+                0.0
+            } else {
+                latencies[((latencies.len() - 1) as f64 * p).round() as usize]
+            }
+        };
+        let duration_ms = duration.as_secs_f64() * 1000.0;
+        Self {
+            transactions: latencies.len(),
+            duration_ms,
+            tps: latencies.len() as f64 / duration.as_secs_f64(),
+            latency_p50_ms: percentile(0.5),
+            latency_p90_ms: percentile(0.9),
+            latency_p99_ms: percentile(0.99),
+            latency_max_ms: percentile(1.0),
+            max_backlog,
+            dropped,
+        }
+    }
+}
+
+struct LoadUser {
+    keys: (jubjub::PublicKey, jubjub::PrivateKey),
+    index: u32,
+    nonce: u64,
+    balance: u64,
+}
+
+// Random transfers between the users, with correct nonces as long as they are
+// applied in the order they are generated.
+pub struct LoadGen {
+    users: Vec<LoadUser>,
+    rng: StdRng,
+}
+
+impl LoadGen {
+    // Users with empty accounts at indices 0, 1, ...
+    pub fn new(keys: Vec<(jubjub::PublicKey, jubjub::PrivateKey)>, seed: u64) -> Self {
+        Self {
+            users: keys
+                .into_iter()
+                .enumerate()
+                .map(|(i, keys)| LoadUser {
+                    keys,
+                    index: i as u32,
+                    nonce: 0,
+                    balance: 0,
+                })
+                .collect(),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    // Users with their existing accounts, keys without an account are skipped
+    pub fn from_state<K: KvStore>(
+        db: &K,
+        keys: Vec<(jubjub::PublicKey, jubjub::PrivateKey)>,
+        seed: u64,
+    ) -> Result<Self, QueryError> {
+        let query = AccountQuery::new(db, state::CHAIN_STATE_MODEL_VERSION);
+        let mut users = Vec::new();
+        for keys in keys {
+            if let Some((index, acc)) = query.by_pub_key(&keys.0)? {
+                users.push(LoadUser {
+                    keys,
+                    index,
+                    nonce: acc.nonce,
+                    balance: acc.balance,
+                });
+            }
+        }
+        Ok(Self {
+            users,
+            rng: StdRng::seed_from_u64(seed),
+        })
+    }
+
+    fn funded(&self) -> usize {
+        self.users.iter().filter(|u| u.balance > MAX_FEE).count()
+    }
+
+    pub fn deposits(&mut self, amount: u64) -> Vec<DepositWithdraw> {
+        self.users
+            .iter_mut()
+            .map(|u| {
+                u.balance += amount;
+                DepositWithdraw {
+                    index: u.index,
+                    pub_key: u.keys.0.clone(),
+                    amount: amount as i64,
+                }
+            })
+            .collect()
+    }
+
+    pub fn transfer(&mut self) -> Option<ZeroTransaction> {
+        if self.funded() < 2 {
+            return None;
+        }
+        let (src, dst) = loop {
+            let src = self.rng.gen_range(0..self.users.len());
+            let dst = self.rng.gen_range(0..self.users.len());
+            if src != dst && self.users[src].balance > MAX_FEE {
+                break (src, dst);
+            }
+        };
+        let fee = self.rng.gen_range(0..=MAX_FEE);
+        let amount = self
+            .rng
+            .gen_range(1..=std::cmp::min(MAX_AMOUNT, self.users[src].balance - fee));
+        let mut tx = ZeroTransaction {
+            nonce: self.users[src].nonce,
+            src_index: self.users[src].index,
+            dst_index: self.users[dst].index,
+            dst_pub_key: self.users[dst].keys.0.clone(),
+            amount,
+            fee,
+            sig: jubjub::Signature::default(),
+        };
+        tx.sign(self.users[src].keys.1.clone());
+        self.users[src].nonce += 1;
+        self.users[src].balance -= amount + fee;
+        self.users[dst].balance += amount;
+        Some(tx)
+    }
+}
+
+// Arrival time of the i-th transaction, all of them arrive at once without a rate
+fn arrival(start: Instant, i: usize, rate: Option<f64>) -> Instant {
+    match rate {
+        Some(rate) => start + Duration::from_secs_f64(i as f64 / rate),
+        None => start,
+    }
+}
+
+// Benchmarks the bank alone: the transfers are applied on an in-memory state,
+// without the executor, its pipeline or a node. Batches are built and proven
+// one at a time, so this is a lower bound of what the executor can sustain
+// with the same prover.
+pub fn run_bank<B: ProvingBackend>(
+    bank: &Bank<B>,
+    mut gen: LoadGen,
+    transactions: usize,
+    rate: Option<f64>,
+) -> Result<LoadReport, LoadError> {
+    let mut db = state::genesis_db(state::CHAIN_STATE_MODEL_VERSION);
+    let deposits = gen.deposits(INITIAL_BALANCE);
+    for chunk in deposits.chunks(BATCH_SIZE) {
+        let batch = bank
+            .prepare_deposit_withdraw(&db, chunk.to_vec())
+            .map_err(LoadError::BankError)?;
+        db.update(&batch.ops).unwrap();
+    }
+    let txs = (0..transactions)
+        .map(|_| gen.transfer().ok_or(LoadError::NotEnoughFunds))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let start = Instant::now();
+    let mut txs = txs.into_iter().enumerate().peekable();
+    let mut pending = VecDeque::new();
+    let mut latencies = Vec::new();
+    let mut max_backlog = 0;
+    while latencies.len() < transactions {
+        while let Some((i, _)) = txs.peek() {
+            if arrival(start, *i, rate) > Instant::now() {
+                break;
+            }
+            pending.push_back(txs.next().unwrap());
+        }
+        max_backlog = std::cmp::max(max_backlog, pending.len());
+        if pending.is_empty() {
+            if let Some((i, _)) = txs.peek() {
+                std::thread::sleep(
+                    arrival(start, *i, rate).saturating_duration_since(Instant::now()),
+                );
+            }
+            continue;
+        }
+
+        let batch = pending
+            .drain(..std::cmp::min(BATCH_SIZE, pending.len()))
+            .collect::<Vec<_>>();
+        let prepared = bank
+            .prepare_change_state(&db, batch.iter().map(|(_, tx)| tx.clone()).collect())
+            .map_err(LoadError::BankError)?;
+        let ops = prepared.ops.clone();
+        bank.prove_change_state(prepared)
+            .map_err(LoadError::BankError)?;
+        db.update(&ops).unwrap();
+
+        let now = Instant::now();
+        for (i, _) in batch {
+            let latency = now.saturating_duration_since(arrival(start, i, rate));
+            latencies.push(latency.as_secs_f64() * 1000.0);
+        }
+    }
+    Ok(LoadReport::new(latencies, start.elapsed(), max_backlog, 0))
+}
+
+fn call(
+    addr: &str,
+    method: &str,
+    params: serde_json::Value,
+) -> Result<serde_json::Value, LoadError> {
+    let mut resp: serde_json::Value = ureq::post(&format!("http://{}/", addr))
+        .send_json(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": method,
+            "params": params,
+        }))
+        .map_err(Box::new)?
+        .into_json()?;
+    if let Some(e) = resp.get("error") {
+        return Err(LoadError::RpcError(e.to_string()));
+    }
+    Ok(resp["result"].take())
+}
+
+// Submits the transfers to the RPC endpoint of a running executor, and waits
+// for their receipts. Users must already have funded accounts.
+pub fn run_rpc(
+    addr: &str,
+    mut gen: LoadGen,
+    transactions: usize,
+    rate: Option<f64>,
+    timeout: Duration,
+) -> Result<LoadReport, LoadError> {
+    let start = Instant::now();
+    let mut submitted = HashMap::<String, Instant>::new();
+    let mut latencies = Vec::new();
+    let mut max_backlog = 0;
+    let mut dropped = 0;
+    let mut next_poll = start;
+    let mut i = 0;
+    while latencies.len() + dropped < transactions && start.elapsed() < timeout {
+        if i < transactions && arrival(start, i, rate) <= Instant::now() {
+            let tx = gen.transfer().ok_or(LoadError::NotEnoughFunds)?;
+            let hash = receipt_id(&tx);
+            call(addr, "submit_transaction", serde_json::to_value(&tx)?)?;
+            submitted.insert(hash, Instant::now());
+            i += 1;
+            continue;
+        }
+
+        if Instant::now() >= next_poll {
+            let hashes = submitted.keys().cloned().collect::<Vec<_>>();
+            let receipts: Vec<Option<Receipt>> = serde_json::from_value(call(
+                addr,
+                "get_receipts",
+                serde_json::to_value(&hashes)?,
+            )?)?;
+            let mut backlog = 0;
+            for (hash, receipt) in hashes.into_iter().zip(receipts) {
+                match receipt.map(|r| r.status) {
+                    Some(ReceiptStatus::Applied) => {
+                        let time = submitted.remove(&hash).unwrap();
+                        latencies.push(time.elapsed().as_secs_f64() * 1000.0);
+                    }
+                    Some(ReceiptStatus::Dropped) => {
+                        submitted.remove(&hash);
+                        dropped += 1;
+                    }
+                    Some(ReceiptStatus::Submitted) => {}
+                    None => backlog += 1,
+                }
+            }
+            max_backlog = std::cmp::max(max_backlog, backlog);
+            next_poll = Instant::now() + POLL_INTERVAL;
+        }
+
+        let wake = if i < transactions {
+            std::cmp::min(arrival(start, i, rate), next_poll)
+        } else {
+            next_poll
+        };
+        std::thread::sleep(wake.saturating_duration_since(Instant::now()));
+    }
+    if !submitted.is_empty() {
+        warn!(
+            pending = submitted.len(),
+            "Transactions were not applied in time"
+        );
+    }
+    if dropped > 0 {
+        warn!(dropped, "Transactions were dropped");
+    }
+    Ok(LoadReport::new(
+        latencies,
+        start.elapsed(),
+        max_backlog,
+        dropped,
+    ))
+}
@@ -1,51 +1,77 @@
+// The executor is only built with the Groth16 backend, which is the only one
+// the chain verifies. Without it, parts of the bank are left unused.
+#![cfg_attr(not(feature = "groth16"), allow(dead_code))]
+
 #[macro_use]
 extern crate lazy_static;
 
+mod backend;
 mod bank;
+mod bench;
+mod cache;
 mod circuits;
 mod config;
 mod core;
-
-use bazuka::config::blockchain::MPN_CONTRACT_ID;
-use bazuka::core::{PaymentDirection, ZkHasher};
+mod cost;
+mod devnet;
+#[cfg(feature = "groth16")]
+mod executor;
+mod hd;
+mod load;
+#[cfg(feature = "groth16")]
+mod metrics;
+mod node;
+mod proofs;
+#[cfg(feature = "groth16")]
+mod prover;
+mod query;
+mod r1cs;
+mod receipts;
+mod registry;
+#[cfg(feature = "groth16")]
+mod rpc;
+mod state;
+mod tokens;
+mod tree;
+mod wallet;
+mod witness;
+
+#[cfg(feature = "groth16")]
+use backend::Groth16Backend;
+use backend::{DefaultBackend, ProvingBackend};
 use bazuka::crypto::{jubjub, ZkSignatureScheme};
 use bazuka::db::ReadOnlyLevelDbKvStore;
-use bazuka::zk::{DepositWithdraw, ZeroTransaction};
-use bellman::{groth16, Circuit};
+use bellman::Circuit;
+#[cfg(feature = "groth16")]
 use bls12_381::Bls12;
-use rand_core::OsRng;
+use std::collections::BTreeMap;
 use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+#[cfg(feature = "groth16")]
+use std::sync::{Arc, Mutex};
+use structopt::StructOpt;
 use zeekit::BellmanFr;
 
-fn load_params<C: Circuit<BellmanFr> + Default>(
+// Parameters are generated when the file is missing or `use_cache` is false
+fn load_params<B: ProvingBackend, C: Circuit<BellmanFr> + Default>(
     path: &str,
     use_cache: bool,
-) -> groth16::Parameters<Bls12> {
-    if use_cache {
+) -> B::Params {
+    if use_cache && std::path::Path::new(path).exists() {
         let param_file = File::open(path).expect("Unable to open parameters file!");
-        groth16::Parameters::<Bls12>::read(param_file, false /* false for better performance*/)
-            .expect("Unable to read parameters file!")
+        B::read_params(param_file).expect("Unable to read parameters file!")
     } else {
-        let c = C::default();
-
-        let p = groth16::generate_random_parameters::<Bls12, _, _>(c, &mut OsRng).unwrap();
+        let p = B::setup::<C>().unwrap();
         let param_file = File::create(path).expect("Unable to create parameters file!");
-        p.write(param_file)
-            .expect("Unable to write parameters file!");
+        B::write_params(&p, param_file).expect("Unable to write parameters file!");
         p
     }
 }
 
+#[cfg(feature = "groth16")]
 fn vk_to_hex(vk: &bellman::groth16::VerifyingKey<Bls12>) -> String {
-    hex::encode(
-        &bincode::serialize(&unsafe {
-            std::mem::transmute::<
-                bellman::groth16::VerifyingKey<Bls12>,
-                bazuka::zk::groth16::Groth16VerifyingKey,
-            >(vk.clone())
-        })
-        .unwrap(),
-    )
+    hex::encode(&bincode::serialize(&backend::groth16_vk(vk)).unwrap())
 }
 
 fn db_shutter() -> ReadOnlyLevelDbKvStore {
@@ -60,190 +86,829 @@ pub enum ZoroError {
     IoError(#[from] std::io::Error),
     #[error("node error: {0}")]
     NodeError(#[from] bazuka::client::NodeError),
+    #[error("state manager error: {0}")]
+    StateManagerError(#[from] bazuka::zk::StateManagerError),
+    #[error("state error: {0}")]
+    StateError(#[from] state::StateError),
+    #[error("query error: {0}")]
+    QueryError(#[from] query::QueryError),
+    #[error("migration error: {0}")]
+    MigrationError(#[from] state::MigrationError),
+    #[error("bincode error: {0}")]
+    BincodeError(#[from] bincode::Error),
+    #[error("witness error: {0}")]
+    WitnessError(#[from] witness::WitnessError),
+    #[error("synthesis error: {0}")]
+    SynthesisError(#[from] bellman::SynthesisError),
+    #[error("receipt error: {0}")]
+    ReceiptError(#[from] receipts::ReceiptError),
+    #[error("devnet error: {0}")]
+    DevnetError(#[from] devnet::DevnetError),
+    #[error("load error: {0}")]
+    LoadError(#[from] load::LoadError),
+    #[error("hd error: {0}")]
+    HdError(#[from] hd::HdError),
+    #[error("wallet error: {0}")]
+    WalletError(#[from] wallet::WalletError),
+    #[error("json error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("hex error: {0}")]
+    HexError(#[from] hex::FromHexError),
+    #[error("{0} parameters do not match the on-chain VK")]
+    VkMismatch(&'static str),
 }
 
-fn transact(
-    node: bazuka::client::PeerAddress,
-    tx: bazuka::core::TransactionAndDelta,
-) -> Result<bazuka::client::messages::TransactResponse, ZoroError> {
-    Ok(tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()?
-        .block_on(async {
-            let sk =
-                <bazuka::core::Signer as bazuka::crypto::SignatureScheme>::generate_keys(b"dummy")
-                    .1;
-            let (lp, client) = bazuka::client::BazukaClient::connect(sk, node);
-
-            let (res, _) = tokio::join!(
-                async move { Ok::<_, bazuka::client::NodeError>(client.transact(tx).await) },
-                lp
-            );
+#[derive(StructOpt)]
+#[structopt(name = "Zoro", about = "Zeeka's Main Payment Network executor")]
+struct ZoroOpt {
+    /// Log format, text or json
+    #[structopt(long, default_value = "text")]
+    log_format: LogFormat,
+    /// Log level or filter directives, e.g. debug or zoro=debug,info
+    #[structopt(long, default_value = "info")]
+    log_level: String,
+    #[structopt(flatten)]
+    run: RunOpt,
+    #[structopt(subcommand)]
+    command: Option<ZoroCommand>,
+}
 
-            res
-        })??)
-}
-
-fn get_zero_mempool(
-    node: bazuka::client::PeerAddress,
-) -> Result<bazuka::client::messages::GetZeroMempoolResponse, ZoroError> {
-    Ok(tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()?
-        .block_on(async {
-            let sk =
-                <bazuka::core::Signer as bazuka::crypto::SignatureScheme>::generate_keys(b"dummy")
-                    .1;
-            let (lp, client) = bazuka::client::BazukaClient::connect(sk, node);
-
-            let (res, _) = tokio::join!(
-                async move { Ok::<_, bazuka::client::NodeError>(client.get_zero_mempool().await) },
-                lp
-            );
+enum LogFormat {
+    Text,
+    Json,
+}
 
-            res
-        })??)
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format: {}", s)),
+        }
+    }
 }
 
-fn main() {
-    let exec_wallet = bazuka::wallet::Wallet::new(b"Executor".to_vec());
-    let use_cache = true;
-    let update_params = load_params::<circuits::UpdateCircuit>("groth16_mpn_update.dat", use_cache);
-    let deposit_withdraw_params = load_params::<circuits::DepositWithdrawCircuit>(
-        "groth16_mpn_deposit_withdraw.dat",
-        use_cache,
-    );
+// Logs go to stderr, stdout is kept for the output of the commands
+fn init_logging(format: &LogFormat, level: &str) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::new(level))
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(true).init(),
+    }
+}
 
-    let node_addr = bazuka::client::PeerAddress("127.0.0.1:3030".parse().unwrap());
+#[derive(StructOpt)]
+struct RunOpt {
+    /// Store the witness of each proven batch in this directory
+    #[structopt(long)]
+    witness_dir: Option<PathBuf>,
+    /// Address of a prover worker, proving is done locally when not given
+    #[structopt(long = "prover")]
+    provers: Vec<String>,
+    /// Accept transactions through a JSON-RPC endpoint on this address
+    #[structopt(long)]
+    rpc: Option<String>,
+    /// Serve Prometheus metrics on GET /metrics on this address
+    #[structopt(long)]
+    metrics: Option<String>,
+    /// Generate the parameters of the unified MPN circuit, which has no
+    /// on-chain VK yet
+    #[structopt(long)]
+    fresh_mpn_params: bool,
+}
+
+#[derive(StructOpt)]
+enum ZoroCommand {
+    /// Maps the MPN state to the next state model version
+    Migrate {
+        #[structopt(long, default_value = "1")]
+        from: u32,
+        /// Where to write the migrated state data (Bincode encoded)
+        #[structopt(long)]
+        output: Option<PathBuf>,
+    },
+    /// Proves a witness file, written by the executor
+    #[cfg(feature = "groth16")]
+    Prove {
+        witness: PathBuf,
+        /// Where to write the proof (Bincode encoded)
+        #[structopt(long)]
+        output: Option<PathBuf>,
+    },
+    /// Runs a prover worker, accepting witnesses from executors
+    #[cfg(feature = "groth16")]
+    ProverWorker {
+        #[structopt(long, default_value = "127.0.0.1:4040")]
+        listen: String,
+    },
+    /// Exports the R1CS of the circuits, one file per circuit
+    ExportR1cs {
+        #[structopt(long, default_value = ".")]
+        output: PathBuf,
+        /// Write Bincode instead of JSON
+        #[structopt(long)]
+        binary: bool,
+    },
+    /// Reports the number of constraints of each circuit, per gadget and transition
+    Cost {
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Finds the account index of a public key (Hex of its Bincode encoding),
+    /// or the next free index for it
+    AccountIndex { pub_key: String },
+    /// Lists the MPN accounts, or finds a single account by index or public key
+    Accounts {
+        #[structopt(long)]
+        index: Option<u32>,
+        /// Hex of the Bincode encoding of the public key
+        #[structopt(long)]
+        pub_key: Option<String>,
+        #[structopt(long, default_value = "0")]
+        offset: usize,
+        #[structopt(long, default_value = "100")]
+        limit: usize,
+        #[structopt(long)]
+        include_empty: bool,
+    },
+    /// Serves account inclusion proofs over HTTP, at GET /proof/<index>?root=<root>
+    ///
+    /// Proofs are served for the current state of the node and the few states
+    /// before it. A request for an older root fails with 409, and should be
+    /// retried without a root or with the current one.
+    ProofServer {
+        #[structopt(long, default_value = "127.0.0.1:4041")]
+        listen: String,
+    },
+    /// Checks an account inclusion proof (JSON), as returned by the proof server
+    VerifyProof { proof: PathBuf },
+    /// Benchmarks witness generation, synthesis and proving of each circuit
+    Bench {
+        /// Number of funded accounts in the synthetic state
+        #[structopt(long, default_value = "16")]
+        accounts: u32,
+        /// Where to write the results (JSON), printed when not given
+        #[structopt(long)]
+        output: Option<PathBuf>,
+        /// Use the mock backend, which needs no parameters and only checks that
+        /// the constraints are satisfied
+        #[cfg(feature = "mock")]
+        #[structopt(long)]
+        mock: bool,
+    },
+    /// Manages the MPN keys of a wallet file and signs transfers
+    Wallet {
+        #[structopt(long, default_value = "mpn-wallet.json")]
+        path: PathBuf,
+        /// Use the key of this account, derived at m/44'/<account>'
+        #[structopt(long, default_value = "0")]
+        account: u32,
+        /// Use the key at this derivation path instead (e.g. m/44'/0'), only
+        /// hardened indices are supported
+        #[structopt(long)]
+        derivation_path: Option<String>,
+        #[structopt(subcommand)]
+        command: WalletCommand,
+    },
+    /// Prints the receipt of a transaction, given its receipt id (Returned by the RPC)
+    Receipt { id: String },
+    /// Simulates random deposits and transfers, and token deposits and
+    /// transfers, on in-memory MPN states, proving and verifying every batch
+    /// with local parameters (Generated on the first run)
+    Devnet {
+        /// Number of synthetic users
+        #[structopt(long, default_value = "16")]
+        users: u32,
+        /// Number of rounds of deposits and transfers, runs forever when not given
+        #[structopt(long)]
+        rounds: Option<u64>,
+        #[structopt(long, default_value = "0")]
+        seed: u64,
+        /// Regenerate the local parameters even if they exist, e.g. after
+        /// changing a circuit
+        #[structopt(long)]
+        fresh_params: bool,
+    },
+    /// Generates random transfers and reports throughput, latency percentiles
+    /// and the proof backlog. Without --rpc, this only benchmarks the bank:
+    /// batches are built and proven one at a time on an in-memory state with
+    /// the devnet parameters (Generated when missing), without the executor or
+    /// a node.
+    Load {
+        /// Number of keyed accounts
+        #[structopt(long, default_value = "64")]
+        users: u32,
+        #[structopt(long, default_value = "1000")]
+        transactions: usize,
+        /// Transactions per second, all of them are sent at once when not given
+        #[structopt(long)]
+        rate: Option<f64>,
+        #[structopt(long, default_value = "0")]
+        seed: u64,
+        /// Send the transfers to the RPC endpoint of a running executor,
+        /// instead of benchmarking the bank. The accounts are derived from the
+        /// wallet and must already be funded.
+        #[structopt(long)]
+        rpc: Option<String>,
+        #[structopt(long, default_value = "mpn-wallet.json")]
+        wallet: PathBuf,
+        /// Seconds to wait for the receipts, over the RPC
+        #[structopt(long, default_value = "600")]
+        timeout: u64,
+        /// Where to write the report (JSON), printed when not given
+        #[structopt(long)]
+        output: Option<PathBuf>,
+    },
+    /// Prints the constraint-system fingerprint of each circuit
+    Fingerprint {
+        /// Fail if the circuits do not match the recorded fingerprints
+        #[structopt(long)]
+        check: bool,
+        /// Record the fingerprints, after deliberately changing a circuit and
+        /// regenerating its parameters
+        #[structopt(long)]
+        update: bool,
+    },
+}
 
-    /*println!("Update: {}", vk_to_hex(&update_params.vk));
+#[derive(StructOpt)]
+enum WalletCommand {
+    /// Generates a new mnemonic
+    New,
+    /// Creates the wallet from an existing mnemonic, read from stdin
+    Restore,
+    /// Prints the public key of the wallet and its MPN account
+    Info,
+    /// Prints a signed transfer (JSON), as accepted by the RPC endpoint
+    Transfer {
+        /// Public key of the receiver (Hex of its Bincode encoding)
+        #[structopt(long)]
+        to: String,
+        #[structopt(long)]
+        amount: u64,
+        #[structopt(long, default_value = "0")]
+        fee: u64,
+        /// Taken from the MPN account of the wallet when not given
+        #[structopt(long)]
+        nonce: Option<u64>,
+        /// Send the transfer to the RPC endpoint on this address
+        #[structopt(long)]
+        rpc: Option<String>,
+    },
+}
+
+const UPDATE_PARAMS_PATH: &str = "groth16_mpn_update.dat";
+const DEPOSIT_WITHDRAW_PARAMS_PATH: &str = "groth16_mpn_deposit_withdraw.dat";
+const MPN_PARAMS_PATH: &str = "groth16_mpn.dat";
+// Parameters of the devnet, which do not need to match the on-chain VKs
+const DEVNET_UPDATE_PARAMS_PATH: &str = "devnet_groth16_mpn_update.dat";
+const DEVNET_DEPOSIT_WITHDRAW_PARAMS_PATH: &str = "devnet_groth16_mpn_deposit_withdraw.dat";
+const DEVNET_MPN_PARAMS_PATH: &str = "devnet_groth16_mpn.dat";
+const FINGERPRINTS_PATH: &str = "fingerprints.json";
+const RECEIPTS_PATH: &str = "receipts.json";
+
+#[cfg(feature = "groth16")]
+fn prove(witness: PathBuf, output: Option<PathBuf>) -> Result<(), ZoroError> {
+    let witness = witness::Witness::load(witness)?;
+    let inputs = witness.inputs.clone();
+    let (params, proof) = match witness.into_circuit()? {
+        witness::WitnessCircuit::Update(circuit) => {
+            let params =
+                load_params::<Groth16Backend, circuits::UpdateCircuit>(UPDATE_PARAMS_PATH, true);
+            let proof = Groth16Backend::prove(&params, circuit)?;
+            (params, proof)
+        }
+        witness::WitnessCircuit::DepositWithdraw(circuit) => {
+            let params = load_params::<Groth16Backend, circuits::DepositWithdrawCircuit>(
+                DEPOSIT_WITHDRAW_PARAMS_PATH,
+                true,
+            );
+            let proof = Groth16Backend::prove(&params, circuit)?;
+            (params, proof)
+        }
+        witness::WitnessCircuit::Mpn(circuit) => {
+            let params = load_params::<Groth16Backend, circuits::MpnCircuit>(MPN_PARAMS_PATH, true);
+            let proof = Groth16Backend::prove(&params, circuit)?;
+            (params, proof)
+        }
+    };
     println!(
-        "Deposit/Withdraw: {}",
-        vk_to_hex(&deposit_withdraw_params.vk)
-    );*/
+        "Verified: {}",
+        Groth16Backend::verify(
+            &Groth16Backend::verifying_key(&params),
+            inputs.state,
+            inputs.aux_data,
+            inputs.next_state,
+            &proof
+        )
+    );
+    if let Some(path) = output {
+        bincode::serialize_into(File::create(path)?, &proof)?;
+    }
+    Ok(())
+}
 
-    let b = bank::Bank::new(update_params, deposit_withdraw_params);
+fn migrate(from: u32, output: Option<PathBuf>) -> Result<(), ZoroError> {
+    let from = state::StateModelVersion::from_number(from)
+        .ok_or(state::MigrationError::UnknownVersion(from))?;
+    let db = db_shutter().snapshot();
+    let (data, root) = state::migrate(&db, from)?;
+    println!(
+        "Migrated from version {} to {}",
+        from.number(),
+        from.next().unwrap().number()
+    );
+    println!("Root: {:?}", root.state_hash);
+    println!("State size: {}", root.state_size);
+    if let Some(path) = output {
+        bincode::serialize_into(File::create(path)?, &data)?;
+    }
+    Ok(())
+}
 
-    let mut latest_processed = None;
-    let db_shutter = db_shutter();
-    loop {
-        let db = db_shutter.snapshot();
+fn circuits_r1cs() -> Result<Vec<r1cs::R1cs>, ZoroError> {
+    Ok(vec![
+        r1cs::export::<circuits::UpdateCircuit>("update")?,
+        r1cs::export::<circuits::DepositWithdrawCircuit>("deposit_withdraw")?,
+        r1cs::export::<circuits::MpnCircuit>("mpn")?,
+    ])
+}
 
-        if latest_processed == Some(b.root(&db)) {
-            println!("Block is already processed!");
-            std::thread::sleep(std::time::Duration::from_millis(1000));
-            continue;
+fn export_r1cs(output: PathBuf, binary: bool) -> Result<(), ZoroError> {
+    for r1cs in circuits_r1cs()? {
+        let path = output.join(format!(
+            "{}.r1cs.{}",
+            r1cs.circuit,
+            if binary { "bin" } else { "json" }
+        ));
+        if binary {
+            bincode::serialize_into(File::create(&path)?, &r1cs)?;
+        } else {
+            serde_json::to_writer(File::create(&path)?, &r1cs)?;
         }
+        println!(
+            "{}: {} constraints, {} inputs, {} aux variables -> {}",
+            r1cs.circuit,
+            r1cs.constraints.len(),
+            r1cs.num_inputs,
+            r1cs.num_aux,
+            path.display()
+        );
+    }
+    Ok(())
+}
 
-        latest_processed = Some(b.root(&db));
-
-        let mempool = get_zero_mempool(node_addr).unwrap();
-
-        let contract_payments = mempool
-            .deposit_withdraws
-            .iter()
-            .filter(|dw| dw.contract_id == *MPN_CONTRACT_ID)
-            .cloned()
-            .collect::<Vec<_>>();
-
-        let deposit_withdraws = contract_payments
-            .iter()
-            .map(|dw| DepositWithdraw {
-                index: dw.zk_address_index,
-                pub_key: dw.zk_address.clone(),
-                amount: match dw.direction {
-                    PaymentDirection::Deposit(_) => dw.amount as i64,
-                    PaymentDirection::Withdraw(_) => -(dw.amount as i64),
-                },
-            })
-            .collect::<Vec<_>>();
-        println!("{:?}", deposit_withdraws);
-
-        if deposit_withdraws.is_empty() {
-            println!("No deposit/withdraws!");
-            std::thread::sleep(std::time::Duration::from_millis(1000));
-            continue;
+fn cost(json: bool) -> Result<(), ZoroError> {
+    let reports = vec![
+        cost::report::<circuits::UpdateCircuit>("update")?,
+        cost::report::<circuits::DepositWithdrawCircuit>("deposit_withdraw")?,
+        cost::report::<circuits::MpnCircuit>("mpn")?,
+    ];
+    if json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    } else {
+        for report in reports.iter() {
+            report.print();
         }
+    }
+    Ok(())
+}
+
+fn account_index(pub_key: String) -> Result<(), ZoroError> {
+    let pub_key: bazuka::crypto::jubjub::PublicKey = bincode::deserialize(&hex::decode(pub_key)?)?;
+    let db = db_shutter().snapshot();
+    let registry = registry::Registry::for_state(&db, state::CHAIN_STATE_MODEL_VERSION)?;
+    match registry.get(&pub_key) {
+        Some(index) => println!("Registered at index: {}", index),
+        None => match registry.allocate(&pub_key) {
+            Some(index) => println!("Next free index: {}", index),
+            None => println!("MPN state is full!"),
+        },
+    }
+    Ok(())
+}
 
-        let alice_keys = jubjub::JubJub::<ZkHasher>::generate_keys(b"alice");
-        let bob_keys = jubjub::JubJub::<ZkHasher>::generate_keys(b"bob");
-        let charlie_keys = jubjub::JubJub::<ZkHasher>::generate_keys(b"charlie");
-        let alice_index = 0;
-        let bob_index = 1;
-        let charlie_index = 2;
-
-        let (delta, new_root, proof) = b.deposit_withdraw(&db, deposit_withdraws).unwrap();
-
-        let mut update = bazuka::core::Transaction {
-            src: exec_wallet.get_address(),
-            nonce: 1,
-            fee: 0,
-            data: bazuka::core::TransactionData::UpdateContract {
-                contract_id: *MPN_CONTRACT_ID,
-                updates: vec![bazuka::core::ContractUpdate::DepositWithdraw {
-                    deposit_withdraws: contract_payments,
-                    next_state: new_root,
-                    proof: bazuka::zk::ZkProof::Groth16(Box::new(proof)),
-                }],
-            },
-            sig: bazuka::core::Signature::Unsigned,
-        };
-        exec_wallet.sign(&mut update);
-
-        let tx_delta = bazuka::core::TransactionAndDelta {
-            tx: update,
-            state_delta: Some(delta),
-        };
-
-        transact(node_addr, tx_delta).unwrap();
-    }
-
-    /*println!("{:?}", b.balances(&db));
-
-    let mut tx1 = ZeroTransaction {
-        nonce: 0,
-        src_index: alice_index,
-        dst_index: bob_index,
-        dst_pub_key: bob_keys.0.clone(),
-        amount: 200,
-        fee: 1,
-        sig: jubjub::Signature::default(),
+fn accounts(
+    index: Option<u32>,
+    pub_key: Option<String>,
+    offset: usize,
+    limit: usize,
+    include_empty: bool,
+) -> Result<(), ZoroError> {
+    let db = db_shutter().snapshot();
+    let query = query::AccountQuery::new(&db, state::CHAIN_STATE_MODEL_VERSION);
+    let accounts = if let Some(index) = index {
+        vec![(index, query.get(index)?)]
+    } else if let Some(pub_key) = pub_key {
+        let pub_key: bazuka::crypto::jubjub::PublicKey =
+            bincode::deserialize(&hex::decode(pub_key)?)?;
+        query.by_pub_key(&pub_key)?.into_iter().collect()
+    } else {
+        query.list(offset, limit, include_empty)?
     };
-    tx1.sign(alice_keys.1.clone());
-
-    let mut tx2 = ZeroTransaction {
-        nonce: 0,
-        src_index: bob_index,
-        dst_index: alice_index,
-        dst_pub_key: alice_keys.0.clone(),
-        amount: 50,
-        fee: 1,
-        sig: jubjub::Signature::default(),
+    for (index, acc) in accounts {
+        println!(
+            "{}: nonce: {}, balance: {}, address: {:?}",
+            index, acc.nonce, acc.balance, acc.address
+        );
+    }
+    Ok(())
+}
+
+fn verify_proof(path: PathBuf) -> Result<(), ZoroError> {
+    let proof: proofs::InclusionProof = serde_json::from_reader(File::open(path)?)?;
+    println!("Verified: {}", proofs::verify(&proof));
+    Ok(())
+}
+
+fn bench(accounts: u32, output: Option<PathBuf>) -> Result<(), ZoroError> {
+    let b = bank::Bank::<DefaultBackend>::new(
+        load_params::<DefaultBackend, circuits::UpdateCircuit>(UPDATE_PARAMS_PATH, true),
+        load_params::<DefaultBackend, circuits::DepositWithdrawCircuit>(
+            DEPOSIT_WITHDRAW_PARAMS_PATH,
+            true,
+        ),
+        load_params::<DefaultBackend, circuits::MpnCircuit>(MPN_PARAMS_PATH, true),
+    );
+    write_bench(bench::run(&b, accounts), output)
+}
+
+fn write_bench(results: Vec<bench::BenchResult>, output: Option<PathBuf>) -> Result<(), ZoroError> {
+    match output {
+        Some(path) => serde_json::to_writer_pretty(File::create(path)?, &results)?,
+        None => println!("{}", serde_json::to_string_pretty(&results)?),
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn load(
+    users: u32,
+    transactions: usize,
+    rate: Option<f64>,
+    seed: u64,
+    rpc: Option<String>,
+    wallet: PathBuf,
+    timeout: u64,
+    output: Option<PathBuf>,
+) -> Result<(), ZoroError> {
+    let report = if let Some(addr) = rpc {
+        let w = wallet::MpnWallet::load(wallet)?;
+        let keys = (0..users)
+            .map(|i| Ok(w.keys(&hd::account_path(i)?)?))
+            .collect::<Result<Vec<_>, ZoroError>>()?;
+        let gen = load::LoadGen::from_state(&db_shutter().snapshot(), keys, seed)?;
+        load::run_rpc(
+            &addr,
+            gen,
+            transactions,
+            rate,
+            std::time::Duration::from_secs(timeout),
+        )?
+    } else {
+        let b = bank::Bank::<DefaultBackend>::new(
+            load_params::<DefaultBackend, circuits::UpdateCircuit>(DEVNET_UPDATE_PARAMS_PATH, true),
+            load_params::<DefaultBackend, circuits::DepositWithdrawCircuit>(
+                DEVNET_DEPOSIT_WITHDRAW_PARAMS_PATH,
+                true,
+            ),
+            load_params::<DefaultBackend, circuits::MpnCircuit>(DEVNET_MPN_PARAMS_PATH, true),
+        );
+        let keys = (0..users)
+            .map(|i| {
+                jubjub::JubJub::<bazuka::core::ZkHasher>::generate_keys(
+                    format!("load-{}", i).as_bytes(),
+                )
+            })
+            .collect();
+        load::run_bank(&b, load::LoadGen::new(keys, seed), transactions, rate)?
     };
-    tx2.sign(bob_keys.1.clone());
-
-    let mut tx3 = ZeroTransaction {
-        nonce: 1,
-        src_index: bob_index,
-        dst_index: alice_index,
-        dst_pub_key: alice_keys.0.clone(),
-        amount: 647,
-        fee: 2,
-        sig: jubjub::Signature::default(),
+    match output {
+        Some(path) => serde_json::to_writer_pretty(File::create(path)?, &report)?,
+        None => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+fn devnet(users: u32, rounds: Option<u64>, seed: u64, fresh_params: bool) -> Result<(), ZoroError> {
+    let use_cache = !fresh_params;
+    let b = bank::Bank::<DefaultBackend>::new(
+        load_params::<DefaultBackend, circuits::UpdateCircuit>(
+            DEVNET_UPDATE_PARAMS_PATH,
+            use_cache,
+        ),
+        load_params::<DefaultBackend, circuits::DepositWithdrawCircuit>(
+            DEVNET_DEPOSIT_WITHDRAW_PARAMS_PATH,
+            use_cache,
+        ),
+        load_params::<DefaultBackend, circuits::MpnCircuit>(DEVNET_MPN_PARAMS_PATH, use_cache),
+    );
+    devnet::run(&b, users, rounds, seed)?;
+    Ok(())
+}
+
+// Returns false when the circuits do not match the recorded fingerprints
+fn fingerprint(check: bool, update: bool) -> Result<bool, ZoroError> {
+    let fingerprints = circuits_r1cs()?
+        .iter()
+        .map(|r1cs| (r1cs.circuit.clone(), r1cs.fingerprint()))
+        .collect::<BTreeMap<_, _>>();
+    for (circuit, fp) in fingerprints.iter() {
+        println!(
+            "{}: {} ({} constraints, {} inputs, {} aux variables)",
+            circuit, fp.hash, fp.num_constraints, fp.num_inputs, fp.num_aux
+        );
+    }
+    if update {
+        let mut f = File::create(FINGERPRINTS_PATH)?;
+        serde_json::to_writer_pretty(&mut f, &fingerprints)?;
+        writeln!(f)?;
+    }
+    if check {
+        let recorded: BTreeMap<String, r1cs::Fingerprint> =
+            serde_json::from_reader(File::open(FINGERPRINTS_PATH)?)?;
+        let mut matched = true;
+        for (circuit, fp) in fingerprints.iter() {
+            if recorded.get(circuit) != Some(fp) {
+                println!("Circuit {} has changed!", circuit);
+                matched = false;
+            }
+        }
+        return Ok(matched);
+    }
+    Ok(true)
+}
+
+fn wallet(
+    path: PathBuf,
+    account: u32,
+    derivation_path: Option<String>,
+    command: WalletCommand,
+) -> Result<(), ZoroError> {
+    let key_path = match derivation_path {
+        Some(p) => hd::parse_path(&p)?,
+        None => hd::account_path(account)?,
     };
-    tx3.sign(bob_keys.1);
-
-    let mut tx4 = ZeroTransaction {
-        nonce: 1,
-        src_index: alice_index,
-        dst_index: charlie_index,
-        dst_pub_key: charlie_keys.0.clone(),
-        amount: 197,
-        fee: 2,
-        sig: jubjub::Signature::default(),
+    let w = match &command {
+        WalletCommand::New => {
+            let w = wallet::MpnWallet::generate();
+            w.save(&path)?;
+            println!("Mnemonic: {}", w.mnemonic());
+            w
+        }
+        WalletCommand::Restore => {
+            eprint!("Mnemonic: ");
+            let mut mnemonic = String::new();
+            std::io::stdin().read_line(&mut mnemonic)?;
+            let w = wallet::MpnWallet::from_mnemonic(mnemonic.trim())?;
+            w.save(&path)?;
+            w
+        }
+        _ => wallet::MpnWallet::load(&path)?,
     };
-    tx4.sign(alice_keys.1);
+    let pub_key = w.pub_key(&key_path)?;
+    if let WalletCommand::New | WalletCommand::Restore = command {
+        println!("Public key: {}", hex::encode(bincode::serialize(&pub_key)?));
+        return Ok(());
+    }
+
+    let db = db_shutter().snapshot();
+    let query = query::AccountQuery::new(&db, state::CHAIN_STATE_MODEL_VERSION);
+    let account = query.by_pub_key(&pub_key)?;
+    match command {
+        WalletCommand::New | WalletCommand::Restore => unreachable!(),
+        WalletCommand::Info => {
+            println!("Public key: {}", hex::encode(bincode::serialize(&pub_key)?));
+            match account {
+                Some((index, acc)) => println!(
+                    "Index: {}, nonce: {}, balance: {}",
+                    index, acc.nonce, acc.balance
+                ),
+                None => println!("No MPN account!"),
+            }
+        }
+        WalletCommand::Transfer {
+            to,
+            amount,
+            fee,
+            nonce,
+            rpc,
+        } => {
+            let (src_index, src) = account.expect("Wallet has no MPN account!");
+            let dst_pub_key: bazuka::crypto::jubjub::PublicKey =
+                bincode::deserialize(&hex::decode(to)?)?;
+            let dst_index = registry::Registry::for_state(&db, state::CHAIN_STATE_MODEL_VERSION)?
+                .allocate(&dst_pub_key)
+                .expect("MPN state is full!");
+            let tx = w.transfer(
+                &key_path,
+                nonce.unwrap_or(src.nonce),
+                src_index,
+                dst_index,
+                dst_pub_key,
+                amount,
+                fee,
+            )?;
+            if let Some(addr) = rpc {
+                let resp: serde_json::Value = ureq::post(&format!("http://{}/", addr))
+                    .send_json(serde_json::json!({
+                        "jsonrpc": "2.0",
+                        "id": 0,
+                        "method": "submit_transaction",
+                        "params": tx,
+                    }))
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?
+                    .into_json()?;
+                println!("{}", resp);
+            } else {
+                println!("{}", serde_json::to_string_pretty(&tx)?);
+            }
+        }
+    }
+    Ok(())
+}
 
-    b.change_state(&db, vec![tx1, tx2, tx3, tx4]).unwrap();
-    println!("{:?}", b.balances(&db));*/
+fn receipt(id: String) -> Result<(), ZoroError> {
+    match receipts::ReceiptStore::open(RECEIPTS_PATH)?.get(&id) {
+        Some(r) => println!("{}", serde_json::to_string_pretty(r)?),
+        None => println!("Transaction is not included in any batch!"),
+    }
+    Ok(())
+}
+
+fn main() {
+    let opt = ZoroOpt::from_args();
+    init_logging(&opt.log_format, &opt.log_level);
+    match opt.command {
+        #[cfg(feature = "groth16")]
+        None => run(opt.run).unwrap(),
+        #[cfg(not(feature = "groth16"))]
+        None => panic!("The executor needs the groth16 feature!"),
+        Some(ZoroCommand::Migrate { from, output }) => migrate(from, output).unwrap(),
+        #[cfg(feature = "groth16")]
+        Some(ZoroCommand::Prove { witness, output }) => prove(witness, output).unwrap(),
+        #[cfg(feature = "groth16")]
+        Some(ZoroCommand::ProverWorker { listen }) => prover::ProverWorker::new(
+            load_params::<Groth16Backend, circuits::UpdateCircuit>(UPDATE_PARAMS_PATH, true),
+            load_params::<Groth16Backend, circuits::DepositWithdrawCircuit>(
+                DEPOSIT_WITHDRAW_PARAMS_PATH,
+                true,
+            ),
+            load_params::<Groth16Backend, circuits::MpnCircuit>(MPN_PARAMS_PATH, true),
+        )
+        .serve(&listen)
+        .unwrap(),
+        Some(ZoroCommand::ExportR1cs { output, binary }) => export_r1cs(output, binary).unwrap(),
+        Some(ZoroCommand::Cost { json }) => cost(json).unwrap(),
+        Some(ZoroCommand::AccountIndex { pub_key }) => account_index(pub_key).unwrap(),
+        Some(ZoroCommand::Accounts {
+            index,
+            pub_key,
+            offset,
+            limit,
+            include_empty,
+        }) => accounts(index, pub_key, offset, limit, include_empty).unwrap(),
+        Some(ZoroCommand::ProofServer { listen }) => proofs::serve(&db_shutter(), &listen).unwrap(),
+        Some(ZoroCommand::VerifyProof { proof }) => verify_proof(proof).unwrap(),
+        Some(ZoroCommand::Bench {
+            accounts,
+            output,
+            #[cfg(feature = "mock")]
+            mock,
+        }) => {
+            #[cfg(feature = "mock")]
+            if mock {
+                let b = bank::Bank::<backend::MockBackend>::new((), (), ());
+                write_bench(bench::run(&b, accounts), output).unwrap();
+                return;
+            }
+            bench(accounts, output).unwrap()
+        }
+        Some(ZoroCommand::Wallet {
+            path,
+            account,
+            derivation_path,
+            command,
+        }) => wallet(path, account, derivation_path, command).unwrap(),
+        Some(ZoroCommand::Devnet {
+            users,
+            rounds,
+            seed,
+            fresh_params,
+        }) => devnet(users, rounds, seed, fresh_params).unwrap(),
+        Some(ZoroCommand::Load {
+            users,
+            transactions,
+            rate,
+            seed,
+            rpc,
+            wallet,
+            timeout,
+            output,
+        }) => load(
+            users,
+            transactions,
+            rate,
+            seed,
+            rpc,
+            wallet,
+            timeout,
+            output,
+        )
+        .unwrap(),
+        Some(ZoroCommand::Receipt { id }) => receipt(id).unwrap(),
+        Some(ZoroCommand::Fingerprint { check, update }) => {
+            if !fingerprint(check, update).unwrap() {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "groth16")]
+fn run(opt: RunOpt) -> Result<(), ZoroError> {
+    let exec_wallet = bazuka::wallet::Wallet::new(b"Executor".to_vec());
+    let use_cache = true;
+    let update_params =
+        load_params::<Groth16Backend, circuits::UpdateCircuit>(UPDATE_PARAMS_PATH, use_cache);
+    let deposit_withdraw_params = load_params::<Groth16Backend, circuits::DepositWithdrawCircuit>(
+        DEPOSIT_WITHDRAW_PARAMS_PATH,
+        use_cache,
+    );
+    let mpn_params =
+        load_params::<Groth16Backend, circuits::MpnCircuit>(MPN_PARAMS_PATH, !opt.fresh_mpn_params);
+
+    let node_addr = bazuka::client::PeerAddress("127.0.0.1:3030".parse().unwrap());
+
+    if vk_to_hex(&update_params.vk)
+        != hex::encode(&bincode::serialize(
+            &*bazuka::config::blockchain::MPN_UPDATE_VK,
+        )?)
+    {
+        return Err(ZoroError::VkMismatch("update"));
+    }
+    if vk_to_hex(&deposit_withdraw_params.vk)
+        != hex::encode(&bincode::serialize(
+            &*bazuka::config::blockchain::MPN_DEPOSIT_WITHDRAW_VK,
+        )?)
+    {
+        return Err(ZoroError::VkMismatch("deposit/withdraw"));
+    }
+
+    // Proofs are checked with the VKs of the chain, the checks above only catch
+    // mismatching parameters early.
+    let mut b =
+        bank::Bank::<Groth16Backend>::new(update_params, deposit_withdraw_params, mpn_params)
+            .with_vks(
+                bazuka::config::blockchain::MPN_UPDATE_VK.clone(),
+                bazuka::config::blockchain::MPN_DEPOSIT_WITHDRAW_VK.clone(),
+            );
+    if let Some(dir) = opt.witness_dir {
+        b = b.with_witness_dir(dir);
+    }
+    let provers = prover::ProverPool::new(opt.provers);
+
+    let queue = Arc::new(Mutex::new(rpc::TxQueue::default()));
+    let receipts = Arc::new(Mutex::new(
+        receipts::ReceiptStore::open(RECEIPTS_PATH).expect("Unable to open receipts!"),
+    ));
+    if let Some(addr) = opt.metrics {
+        std::thread::spawn(move || metrics::serve(&addr).unwrap());
+    }
+    if let Some(addr) = opt.rpc {
+        let server = rpc::RpcServer::new(queue.clone(), receipts.clone(), db_shutter());
+        std::thread::spawn(move || server.serve(&addr).unwrap());
+    }
+
+    executor::Executor::new(b, provers, node_addr, exec_wallet, queue, receipts).run(db_shutter());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Changing a circuit changes its keys, record the new fingerprints with
+    // `zoro fingerprint --update` when that's deliberate.
+    #[test]
+    #[ignore = "fingerprints.json is not recorded yet, run `zoro fingerprint --update`"]
+    fn test_fingerprints() {
+        let recorded: BTreeMap<String, r1cs::Fingerprint> = serde_json::from_reader(
+            File::open(FINGERPRINTS_PATH).expect("Fingerprints are not recorded!"),
+        )
+        .unwrap();
+        let circuits = circuits_r1cs().unwrap();
+        assert_eq!(recorded.len(), circuits.len());
+        for r1cs in circuits {
+            assert_eq!(
+                recorded.get(&r1cs.circuit),
+                Some(&r1cs.fingerprint()),
+                "Circuit {} has changed!",
+                r1cs.circuit
+            );
+        }
+    }
 }
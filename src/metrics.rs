@@ -0,0 +1,99 @@
+use crate::bank::BankError;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use tracing::{info, warn};
+
+// Circuits are labeled by the same names as the witnesses and batch spans
+// (deposit_withdraw and update).
+
+lazy_static! {
+    pub static ref MEMPOOL_SIZE: IntGaugeVec = register_int_gauge_vec!(
+        "zoro_mempool_size",
+        "Number of MPN transactions in the mempool of the node, by kind",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref QUEUE_SIZE: IntGaugeVec = register_int_gauge_vec!(
+        "zoro_rpc_queue_size",
+        "Number of transactions submitted through the RPC, waiting to be applied, by kind",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref BATCHES_PROVEN: IntCounterVec = register_int_counter_vec!(
+        "zoro_batches_proven_total",
+        "Number of batches proven, by circuit",
+        &["circuit"]
+    )
+    .unwrap();
+    pub static ref BATCHES_SUBMITTED: IntCounterVec = register_int_counter_vec!(
+        "zoro_batches_submitted_total",
+        "Number of batches submitted to the node, by circuit",
+        &["circuit"]
+    )
+    .unwrap();
+    pub static ref PROVING_DURATION: HistogramVec = register_histogram_vec!(
+        "zoro_proving_duration_seconds",
+        "Time spent proving a batch, by circuit",
+        &["circuit"],
+        prometheus::exponential_buckets(0.5, 2.0, 10).unwrap()
+    )
+    .unwrap();
+    pub static ref BANK_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "zoro_bank_errors_total",
+        "Number of bank errors, by variant",
+        &["variant"]
+    )
+    .unwrap();
+    pub static ref STATE_ROOT_AGE: IntGauge = register_int_gauge!(
+        "zoro_state_root_age_seconds",
+        "Seconds since the MPN state root last changed on the chain"
+    )
+    .unwrap();
+    pub static ref NODE_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "zoro_node_errors_total",
+        "Number of failed requests to the node, by method",
+        &["method"]
+    )
+    .unwrap();
+}
+
+pub fn bank_error(e: &BankError) {
+    BANK_ERRORS.with_label_values(&[&format!("{:?}", e)]).inc();
+}
+
+fn gather() -> Result<Vec<u8>, prometheus::Error> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(buffer)
+}
+
+// Serves the metrics in the Prometheus text format on GET /metrics
+pub fn serve(addr: &str) -> Result<(), std::io::Error> {
+    let server = tiny_http::Server::http(addr)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    info!(addr, "Metrics listening");
+    for request in server.incoming_requests() {
+        let response =
+            if request.method() != &tiny_http::Method::Get || request.url() != "/metrics" {
+                tiny_http::Response::from_data(b"Not found!".to_vec()).with_status_code(404)
+            } else {
+                match gather() {
+                    Ok(body) => tiny_http::Response::from_data(body).with_header(
+                        tiny_http::Header::from_bytes(
+                            &b"Content-Type"[..],
+                            TextEncoder::new().format_type().as_bytes(),
+                        )
+                        .unwrap(),
+                    ),
+                    Err(e) => tiny_http::Response::from_data(e.to_string().into_bytes())
+                        .with_status_code(500),
+                }
+            };
+        if let Err(e) = request.respond(response) {
+            warn!(error = %e, "Unable to respond");
+        }
+    }
+    Ok(())
+}
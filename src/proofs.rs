@@ -0,0 +1,262 @@
+use crate::cache::hash_account;
+use crate::config::LOG4_TREE_SIZE;
+use crate::query::{AccountQuery, QueryError};
+use crate::state::CHAIN_STATE_MODEL_VERSION;
+use crate::tree;
+use crate::witness::AccountWitness;
+use bazuka::config::blockchain::MPN_CONTRACT_ID;
+use bazuka::core::ZkHasher;
+use bazuka::db::{KvStore, ReadOnlyLevelDbKvStore};
+use bazuka::zk::{KvStoreStateManager, StateManagerError, ZkDataLocator, ZkScalar};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// Protocol of the inclusion-proof service:
+//
+// GET /proof/<index>?root=<root> returns a JSON `InclusionProof` of the account
+// at <index>. The root is the hex of the Bincode encoding of the state root, and
+// is optional, the current state is used when it's not given. Only the last
+// `RECENT_STATES` states seen by the service are kept, a request for an older
+// (Or unknown) root fails with 409. Malformed requests fail with 400, and
+// errors reading the state with 500.
+
+const RECENT_STATES: usize = 16;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InclusionProof {
+    pub index: u32,
+    pub account: AccountWitness,
+    pub leaf: ZkScalar,
+    pub proof: Vec<[ZkScalar; 3]>,
+    pub root: ZkScalar,
+}
+
+// Recomputes the root the same way the update and deposit/withdraw circuits do
+pub fn verify(p: &InclusionProof) -> bool {
+    p.leaf == hash_account(CHAIN_STATE_MODEL_VERSION, &p.account.clone().into())
+        && tree::calc_root(p.index as u64, p.leaf, &p.proof) == p.root
+}
+
+pub fn prove<K: KvStore>(db: &K, index: u32) -> Result<InclusionProof, QueryError> {
+    let account = AccountQuery::new(db, CHAIN_STATE_MODEL_VERSION).get(index)?;
+    let proof =
+        KvStoreStateManager::<ZkHasher>::prove(db, *MPN_CONTRACT_ID, ZkDataLocator(vec![]), index)?;
+    let root =
+        KvStoreStateManager::<ZkHasher>::get_data(db, *MPN_CONTRACT_ID, &ZkDataLocator(vec![]))?;
+    Ok(InclusionProof {
+        index,
+        leaf: hash_account(CHAIN_STATE_MODEL_VERSION, &account),
+        account: (&account).into(),
+        proof,
+        root,
+    })
+}
+
+// Snapshots of the most recent states, by their roots. The last one is the
+// current state.
+pub struct RecentStates<K: KvStore> {
+    states: VecDeque<(ZkScalar, K)>,
+}
+
+impl<K: KvStore> Default for RecentStates<K> {
+    fn default() -> Self {
+        Self {
+            states: VecDeque::new(),
+        }
+    }
+}
+
+impl<K: KvStore> RecentStates<K> {
+    // Keeps the snapshot as the current state, the snapshot of the oldest
+    // state is released when there are too many.
+    pub fn observe(&mut self, snapshot: K) -> Result<(), StateManagerError> {
+        let root = KvStoreStateManager::<ZkHasher>::get_data(
+            &snapshot,
+            *MPN_CONTRACT_ID,
+            &ZkDataLocator(vec![]),
+        )?;
+        if self.states.back().map(|(r, _)| *r == root).unwrap_or(false) {
+            return Ok(());
+        }
+        self.states.retain(|(r, _)| *r != root);
+        if self.states.len() >= RECENT_STATES {
+            self.states.pop_front();
+        }
+        self.states.push_back((root, snapshot));
+        Ok(())
+    }
+
+    // The state with the given root, or the current state
+    pub fn get(&self, root: Option<ZkScalar>) -> Option<&K> {
+        match root {
+            Some(root) => self.states.iter().find(|(r, _)| *r == root),
+            None => self.states.back(),
+        }
+        .map(|(_, snapshot)| snapshot)
+    }
+}
+
+fn handle<K: KvStore>(states: &RecentStates<K>, url: &str) -> Result<String, (u16, String)> {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let index = path
+        .strip_prefix("/proof/")
+        .ok_or((404, "Not found!".to_string()))?
+        .parse::<u32>()
+        .map_err(|e| (400, e.to_string()))?;
+    if index as u64 >= 1 << (2 * LOG4_TREE_SIZE as u64) {
+        return Err((400, "Index out of range!".to_string()));
+    }
+    let root = query
+        .split('&')
+        .find_map(|p| p.strip_prefix("root="))
+        .map(|r| {
+            hex::decode(r)
+                .map_err(|e| e.to_string())
+                .and_then(|b| bincode::deserialize::<ZkScalar>(&b).map_err(|e| e.to_string()))
+                .map_err(|e| (400, e))
+        })
+        .transpose()?;
+
+    let db = states
+        .get(root)
+        .ok_or((409, "State root is not available!".to_string()))?;
+    let proof = prove(db, index).map_err(|e| (500, e.to_string()))?;
+    serde_json::to_string(&proof).map_err(|e| (500, e.to_string()))
+}
+
+pub fn serve(db_shutter: &ReadOnlyLevelDbKvStore, addr: &str) -> Result<(), std::io::Error> {
+    let server = tiny_http::Server::http(addr)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    info!(addr, "Proof service listening");
+    let mut states = RecentStates::default();
+    loop {
+        // The state is polled even without requests, so that the roots of the
+        // recent states are known.
+        if let Err(e) = states.observe(db_shutter.snapshot()) {
+            warn!(error = %e, "Cannot read the state");
+        }
+        let request = match server.recv_timeout(POLL_INTERVAL)? {
+            Some(request) => request,
+            None => continue,
+        };
+        let response = if request.method() != &tiny_http::Method::Get {
+            tiny_http::Response::from_string("Not found!").with_status_code(404)
+        } else {
+            match handle(&states, request.url()) {
+                Ok(body) => tiny_http::Response::from_string(body),
+                Err((status, e)) => tiny_http::Response::from_string(e).with_status_code(status),
+            }
+        };
+        if let Err(e) = request.respond(response) {
+            warn!(error = %e, "Unable to respond");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core;
+    use crate::state::{genesis_db, set_account};
+    use bazuka::crypto::{jubjub, ZkSignatureScheme};
+    use bazuka::db::RamKvStore;
+
+    fn account(name: &str, balance: u64) -> core::Account {
+        let (pub_key, _) = jubjub::JubJub::<ZkHasher>::generate_keys(name.as_bytes());
+        core::Account {
+            nonce: 0,
+            address: pub_key.0.decompress(),
+            balance,
+            tokens: Default::default(),
+        }
+    }
+
+    fn root_param(root: ZkScalar) -> String {
+        hex::encode(bincode::serialize(&root).unwrap())
+    }
+
+    fn request<K: KvStore>(states: &RecentStates<K>, url: &str) -> InclusionProof {
+        serde_json::from_str(&handle(states, url).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut db = genesis_db(CHAIN_STATE_MODEL_VERSION);
+        set_account(&mut db, CHAIN_STATE_MODEL_VERSION, 3, account("alice", 100)).unwrap();
+        set_account(&mut db, CHAIN_STATE_MODEL_VERSION, 9, account("bob", 50)).unwrap();
+        for index in [3, 9, 4] {
+            assert!(verify(&prove(&db, index).unwrap()));
+        }
+
+        let proof = prove(&db, 3).unwrap();
+        let mut tampered = proof.clone();
+        tampered.index = 4;
+        assert!(!verify(&tampered));
+        let mut tampered = proof.clone();
+        tampered.account.balance += 1;
+        assert!(!verify(&tampered));
+        let mut tampered = proof;
+        tampered.root = ZkScalar::from(1);
+        assert!(!verify(&tampered));
+    }
+
+    #[test]
+    fn test_recent_roots() {
+        let mut states = RecentStates::<RamKvStore>::default();
+        let mut db = genesis_db(CHAIN_STATE_MODEL_VERSION);
+        set_account(&mut db, CHAIN_STATE_MODEL_VERSION, 3, account("alice", 100)).unwrap();
+        states.observe(db.clone()).unwrap();
+        let old = prove(&db, 3).unwrap();
+
+        set_account(&mut db, CHAIN_STATE_MODEL_VERSION, 3, account("alice", 70)).unwrap();
+        states.observe(db.clone()).unwrap();
+        let current = prove(&db, 3).unwrap();
+        assert_ne!(old.root, current.root);
+
+        // The state before the current one is still served
+        let proof = request(&states, &format!("/proof/3?root={}", root_param(old.root)));
+        assert!(verify(&proof));
+        assert_eq!(proof.root, old.root);
+        assert_eq!(proof.account.balance, 100);
+
+        let proof = request(&states, "/proof/3");
+        assert!(verify(&proof));
+        assert_eq!(proof.root, current.root);
+        assert_eq!(proof.account.balance, 70);
+
+        assert_eq!(
+            handle(
+                &states,
+                &format!("/proof/3?root={}", root_param(ZkScalar::from(1)))
+            )
+            .unwrap_err()
+            .0,
+            409
+        );
+    }
+
+    #[test]
+    fn test_old_roots_are_released() {
+        let mut states = RecentStates::<RamKvStore>::default();
+        let mut db = genesis_db(CHAIN_STATE_MODEL_VERSION);
+        let mut roots = Vec::new();
+        for balance in 0..=RECENT_STATES as u64 {
+            set_account(
+                &mut db,
+                CHAIN_STATE_MODEL_VERSION,
+                0,
+                account("alice", balance + 1),
+            )
+            .unwrap();
+            states.observe(db.clone()).unwrap();
+            roots.push(prove(&db, 0).unwrap().root);
+        }
+        assert!(states.get(Some(roots[0])).is_none());
+        for root in roots[1..].iter() {
+            assert!(states.get(Some(*root)).is_some());
+        }
+    }
+}
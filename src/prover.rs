@@ -0,0 +1,179 @@
+use crate::backend::{Groth16Backend, ProvingBackend};
+use crate::witness::{Witness, WitnessCircuit};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tracing::{info, warn};
+
+// Protocol between the executor and the prover workers:
+//
+// POST /prove with a JSON `Witness` as the body. Workers prove one witness at a
+// time and reply with a JSON `ProveResponse`, or with a non-200 status and the
+// error message as the body.
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProveResponse<P> {
+    pub proof: P,
+}
+
+#[derive(Error, Debug)]
+pub enum ProverError {
+    #[error("http error: {0}")]
+    HttpError(#[from] Box<ureq::Error>),
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("json error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("no prover workers")]
+    NoWorkers,
+}
+
+pub struct ProverWorker {
+    update_params: <Groth16Backend as ProvingBackend>::Params,
+    deposit_withdraw_params: <Groth16Backend as ProvingBackend>::Params,
+    mpn_params: <Groth16Backend as ProvingBackend>::Params,
+}
+
+impl ProverWorker {
+    pub fn new(
+        update_params: <Groth16Backend as ProvingBackend>::Params,
+        deposit_withdraw_params: <Groth16Backend as ProvingBackend>::Params,
+        mpn_params: <Groth16Backend as ProvingBackend>::Params,
+    ) -> Self {
+        Self {
+            update_params,
+            deposit_withdraw_params,
+            mpn_params,
+        }
+    }
+
+    fn prove(
+        &self,
+        witness: Witness,
+    ) -> Result<ProveResponse<bazuka::zk::groth16::Groth16Proof>, String> {
+        let start = std::time::Instant::now();
+        let proof = match witness.into_circuit().map_err(|e| e.to_string())? {
+            WitnessCircuit::Update(circuit) => Groth16Backend::prove(&self.update_params, circuit),
+            WitnessCircuit::DepositWithdraw(circuit) => {
+                Groth16Backend::prove(&self.deposit_withdraw_params, circuit)
+            }
+            WitnessCircuit::Mpn(circuit) => Groth16Backend::prove(&self.mpn_params, circuit),
+        }
+        .map_err(|e| e.to_string())?;
+        info!(
+            proving_ms = start.elapsed().as_millis() as u64,
+            "Proof generated"
+        );
+        Ok(ProveResponse { proof })
+    }
+
+    pub fn serve(&self, addr: &str) -> Result<(), ProverError> {
+        let server = tiny_http::Server::http(addr)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        info!(addr, "Prover worker listening");
+        for mut request in server.incoming_requests() {
+            let response =
+                if request.method() != &tiny_http::Method::Post || request.url() != "/prove" {
+                    tiny_http::Response::from_string("Not found!").with_status_code(404)
+                } else {
+                    match serde_json::from_reader::<_, Witness>(request.as_reader())
+                        .map_err(|e| e.to_string())
+                        .and_then(|witness| self.prove(witness))
+                        .and_then(|resp| serde_json::to_string(&resp).map_err(|e| e.to_string()))
+                    {
+                        Ok(body) => tiny_http::Response::from_string(body),
+                        Err(e) => tiny_http::Response::from_string(e).with_status_code(400),
+                    }
+                };
+            if let Err(e) = request.respond(response) {
+                warn!(error = %e, "Unable to respond");
+            }
+        }
+        Ok(())
+    }
+}
+
+// Dispatches witnesses to the prover workers, picking the worker with the
+// fewest witnesses in flight (An idle one, if any). A witness is retried on the
+// next least busy worker when a worker fails.
+#[derive(Clone)]
+pub struct ProverPool {
+    workers: Vec<String>,
+    in_flight: Arc<Mutex<Vec<usize>>>,
+}
+
+impl ProverPool {
+    pub fn new(workers: Vec<String>) -> Self {
+        let in_flight = vec![0; workers.len()];
+        Self {
+            workers,
+            in_flight: Arc::new(Mutex::new(in_flight)),
+        }
+    }
+
+    // Picks the least busy of the workers not tried yet, counting the witness
+    // as in flight on it until it's released.
+    fn acquire(&self, tried: &[bool]) -> Option<usize> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let worker = (0..self.workers.len())
+            .filter(|i| !tried[*i])
+            .min_by_key(|i| in_flight[*i])?;
+        in_flight[worker] += 1;
+        Some(worker)
+    }
+
+    fn release(&self, worker: usize) {
+        self.in_flight.lock().unwrap()[worker] -= 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    fn request<P: DeserializeOwned>(worker: &str, witness: &Witness) -> Result<P, ProverError> {
+        let resp: ProveResponse<P> = ureq::post(&format!("http://{}/prove", worker))
+            .send_json(serde_json::to_value(witness)?)
+            .map_err(Box::new)?
+            .into_json()?;
+        Ok(resp.proof)
+    }
+
+    // Tries each worker at most once, returning the error of the last one when
+    // all of them fail.
+    pub fn prove<P: DeserializeOwned>(&self, witness: &Witness) -> Result<P, ProverError> {
+        let mut tried = vec![false; self.workers.len()];
+        let mut last_err = ProverError::NoWorkers;
+        while let Some(i) = self.acquire(&tried) {
+            tried[i] = true;
+            let worker = &self.workers[i];
+            let res = Self::request::<P>(worker, witness);
+            self.release(i);
+            match res {
+                Ok(proof) => return Ok(proof),
+                Err(e) => {
+                    warn!(worker = %worker, error = %e, "Worker failed");
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire_least_busy() {
+        let pool = ProverPool::new(vec!["a".into(), "b".into(), "c".into()]);
+        let none = [false; 3];
+        assert_eq!(pool.acquire(&none), Some(0));
+        assert_eq!(pool.acquire(&none), Some(1));
+        pool.release(0);
+        assert_eq!(pool.acquire(&none), Some(0));
+        assert_eq!(pool.acquire(&[true, false, false]), Some(2));
+        assert_eq!(pool.acquire(&[true, true, true]), None);
+    }
+}
@@ -0,0 +1,225 @@
+use crate::config::LOG4_TREE_SIZE;
+use crate::core;
+use crate::registry::Registry;
+use crate::state::{AccountField, StateError, StateModelVersion, TokenAccountField, TokenField};
+use bazuka::core::ZkHasher;
+use bazuka::crypto::jubjub::PublicKey;
+use bazuka::zk::{KvStoreStateManager, StateManagerError, ZkDataLocator, ZkScalar};
+use bazuka::{config::blockchain::MPN_CONTRACT_ID, db::KvStore};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum QueryError {
+    #[error("state manager error: {0}")]
+    StateManagerError(#[from] StateManagerError),
+    #[error("state error: {0}")]
+    StateError(#[from] StateError),
+    #[error("account {0} has invalid data")]
+    InvalidAccount(u32),
+    #[error("account index {0} is out of range")]
+    IndexOutOfRange(u32),
+}
+
+fn capacity() -> u64 {
+    1 << (2 * LOG4_TREE_SIZE as u64)
+}
+
+fn to_u64(index: u32, val: ZkScalar) -> Result<u64, QueryError> {
+    val.try_into()
+        .map_err(|_| QueryError::InvalidAccount(index))
+}
+
+// Read-only queries over the MPN accounts of a database, with the given state
+// model version
+pub struct AccountQuery<'a, K: KvStore> {
+    db: &'a K,
+    version: StateModelVersion,
+}
+
+impl<'a, K: KvStore> AccountQuery<'a, K> {
+    pub fn new(db: &'a K, version: StateModelVersion) -> Self {
+        Self { db, version }
+    }
+
+    fn get_data(&self, locator: ZkDataLocator) -> Result<ZkScalar, QueryError> {
+        Ok(KvStoreStateManager::<ZkHasher>::get_data(
+            self.db,
+            *MPN_CONTRACT_ID,
+            &locator,
+        )?)
+    }
+
+    pub fn get(&self, index: u32) -> Result<core::Account, QueryError> {
+        if index as u64 >= capacity() {
+            return Err(QueryError::IndexOutOfRange(index));
+        }
+        let mut acc = core::Account {
+            nonce: to_u64(index, self.get_data(AccountField::Nonce.at(&[index]))?)?,
+            balance: to_u64(index, self.get_data(AccountField::Balance.at(&[index]))?)?,
+            ..Default::default()
+        };
+        acc.address.0 = self.get_data(AccountField::PubKeyX.at(&[index]))?;
+        acc.address.1 = self.get_data(AccountField::PubKeyY.at(&[index]))?;
+        for (token_index, token) in acc
+            .tokens
+            .iter_mut()
+            .take(self.version.tokens_per_account())
+            .enumerate()
+        {
+            let prefix = [index, TokenAccountField::Tokens as u32, token_index as u32];
+            token.token_id = self.get_data(TokenField::TokenId.at(&prefix))?;
+            token.amount = to_u64(index, self.get_data(TokenField::Amount.at(&prefix))?)?;
+        }
+        Ok(acc)
+    }
+
+    pub fn by_pub_key(
+        &self,
+        pub_key: &PublicKey,
+    ) -> Result<Option<(u32, core::Account)>, QueryError> {
+        match Registry::for_state(self.db, self.version)?.get(pub_key) {
+            Some(index) => Ok(Some((index, self.get(index)?))),
+            None => Ok(None),
+        }
+    }
+
+    // Non-empty accounts, ordered by index
+    fn accounts(&self) -> Result<BTreeMap<u32, core::Account>, QueryError> {
+        let full_state =
+            KvStoreStateManager::<ZkHasher>::get_full_state(self.db, *MPN_CONTRACT_ID)?;
+        let mut accounts = BTreeMap::<u32, core::Account>::new();
+        for (loc, val) in full_state.data.0 {
+            let index = loc.0[0];
+            let acc = accounts.entry(index).or_default();
+            match loc.0[1..] {
+                [f] if f == AccountField::Nonce as u32 => acc.nonce = to_u64(index, val)?,
+                [f] if f == AccountField::PubKeyX as u32 => acc.address.0 = val,
+                [f] if f == AccountField::PubKeyY as u32 => acc.address.1 = val,
+                [f] if f == AccountField::Balance as u32 => acc.balance = to_u64(index, val)?,
+                [f, t, field]
+                    if f == TokenAccountField::Tokens as u32
+                        && (t as usize) < self.version.tokens_per_account() =>
+                {
+                    let token = &mut acc.tokens[t as usize];
+                    if field == TokenField::TokenId as u32 {
+                        token.token_id = val;
+                    } else if field == TokenField::Amount as u32 {
+                        token.amount = to_u64(index, val)?;
+                    } else {
+                        return Err(QueryError::InvalidAccount(index));
+                    }
+                }
+                _ => return Err(QueryError::InvalidAccount(index)),
+            }
+        }
+        Ok(accounts)
+    }
+
+    // A page of accounts, ordered by index. When `include_empty` is set, pages
+    // are over all the indices of the tree, otherwise only over the non-empty
+    // accounts.
+    pub fn list(
+        &self,
+        offset: usize,
+        limit: usize,
+        include_empty: bool,
+    ) -> Result<Vec<(u32, core::Account)>, QueryError> {
+        let mut accounts = self.accounts()?;
+        if include_empty {
+            Ok((offset as u64..capacity())
+                .take(limit)
+                .map(|i| {
+                    let index = i as u32;
+                    (index, accounts.remove(&index).unwrap_or_default())
+                })
+                .collect())
+        } else {
+            Ok(accounts.into_iter().skip(offset).take(limit).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{genesis_db, set_account, CHAIN_STATE_MODEL_VERSION};
+    use bazuka::crypto::{jubjub, ZkSignatureScheme};
+    use bazuka::db::RamKvStore;
+
+    const INDICES: [u32; 3] = [0, 3, 7];
+
+    fn db() -> RamKvStore {
+        let mut db = genesis_db(CHAIN_STATE_MODEL_VERSION);
+        for index in INDICES {
+            let (pub_key, _) =
+                jubjub::JubJub::<ZkHasher>::generate_keys(format!("user-{}", index).as_bytes());
+            let acc = core::Account {
+                nonce: 1,
+                address: pub_key.0.decompress(),
+                balance: 100 + index as u64,
+                tokens: Default::default(),
+            };
+            set_account(&mut db, CHAIN_STATE_MODEL_VERSION, index, acc).unwrap();
+        }
+        db
+    }
+
+    fn indices(accounts: Vec<(u32, core::Account)>) -> Vec<u32> {
+        accounts.into_iter().map(|(index, _)| index).collect()
+    }
+
+    #[test]
+    fn test_list_skips_empty_accounts() {
+        let db = db();
+        let query = AccountQuery::new(&db, CHAIN_STATE_MODEL_VERSION);
+        assert_eq!(indices(query.list(0, 2, false).unwrap()), vec![0, 3]);
+        assert_eq!(indices(query.list(2, 10, false).unwrap()), vec![7]);
+        assert!(query.list(3, 10, false).unwrap().is_empty());
+        assert!(query.list(0, 0, false).unwrap().is_empty());
+        for (index, acc) in query.list(0, usize::MAX, false).unwrap() {
+            assert_eq!(acc.balance, 100 + index as u64);
+        }
+    }
+
+    #[test]
+    fn test_list_with_empty_accounts() {
+        let db = db();
+        let query = AccountQuery::new(&db, CHAIN_STATE_MODEL_VERSION);
+        let page = query.list(2, 3, true).unwrap();
+        assert_eq!(indices(page.clone()), vec![2, 3, 4]);
+        assert_eq!(page[0].1, core::Account::default());
+        assert_eq!(page[1].1.balance, 103);
+
+        let last = capacity() as usize - 1;
+        assert_eq!(
+            indices(query.list(last, 10, true).unwrap()),
+            vec![last as u32]
+        );
+        assert!(query.list(last + 1, 10, true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_out_of_range() {
+        let db = db();
+        let query = AccountQuery::new(&db, CHAIN_STATE_MODEL_VERSION);
+        assert_eq!(query.get(3).unwrap().balance, 103);
+        assert_eq!(query.get(4).unwrap(), core::Account::default());
+        assert!(matches!(
+            query.get(capacity() as u32),
+            Err(QueryError::IndexOutOfRange(_))
+        ));
+    }
+
+    #[test]
+    fn test_by_pub_key() {
+        let db = db();
+        let query = AccountQuery::new(&db, CHAIN_STATE_MODEL_VERSION);
+        let (known, _) = jubjub::JubJub::<ZkHasher>::generate_keys(b"user-3");
+        let (unknown, _) = jubjub::JubJub::<ZkHasher>::generate_keys(b"unknown");
+        let (index, acc) = query.by_pub_key(&known).unwrap().unwrap();
+        assert_eq!(index, 3);
+        assert_eq!(acc.address, known.0.decompress());
+        assert!(query.by_pub_key(&unknown).unwrap().is_none());
+    }
+}
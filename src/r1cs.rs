@@ -0,0 +1,233 @@
+use bellman::{Circuit, ConstraintSystem, Index, LinearCombination, SynthesisError, Variable};
+use ff::{Field, PrimeField};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use zeekit::BellmanFr;
+
+pub const R1CS_VERSION: u32 = 1;
+
+// Modulus of the BLS12-381 scalar field
+pub const FIELD_MODULUS: &str =
+    "0x73eda753299d7d483339d80809a1d80553bda402fffe5bfeffffffff00000001";
+
+// Field elements are big-endian hex strings, prefixed with `0x`
+pub fn encode_scalar(s: &BellmanFr) -> String {
+    let mut bytes = s.to_repr().as_ref().to_vec();
+    bytes.reverse();
+    format!("0x{}", hex::encode(bytes))
+}
+
+// Coefficient of a wire in a linear combination
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Term {
+    pub wire: usize,
+    pub coeff: String,
+}
+
+// Enforces: <a, w> * <b, w> = <c, w>
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Constraint {
+    pub name: String,
+    pub a: Vec<Term>,
+    pub b: Vec<Term>,
+    pub c: Vec<Term>,
+}
+
+// The wire vector `w` is laid out as: the constant one (Wire 0), followed by the
+// `num_inputs - 1` public inputs in the order the verifier receives them, and then
+// the `num_aux` private wires. `wires` holds the name of each wire.
+//
+// Written as JSON, or as Bincode (Binary) with the same structure.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct R1cs {
+    pub version: u32,
+    pub circuit: String,
+    pub field_modulus: String,
+    pub num_inputs: usize,
+    pub num_aux: usize,
+    pub wires: Vec<String>,
+    pub constraints: Vec<Constraint>,
+}
+
+// Identifies the shape of a constraint system. Names of wires and constraints
+// are not part of the hash, as they do not affect the proving/verifying keys.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub num_inputs: usize,
+    pub num_aux: usize,
+    pub num_constraints: usize,
+    pub hash: String,
+}
+
+impl R1cs {
+    pub fn fingerprint(&self) -> Fingerprint {
+        let mut hasher = Sha256::new();
+        hasher.update((self.num_inputs as u64).to_le_bytes());
+        hasher.update((self.num_aux as u64).to_le_bytes());
+        hasher.update((self.constraints.len() as u64).to_le_bytes());
+        for constraint in self.constraints.iter() {
+            for lc in [&constraint.a, &constraint.b, &constraint.c] {
+                hasher.update((lc.len() as u64).to_le_bytes());
+                for term in lc.iter() {
+                    hasher.update((term.wire as u64).to_le_bytes());
+                    hasher.update(term.coeff.as_bytes());
+                }
+            }
+        }
+        Fingerprint {
+            num_inputs: self.num_inputs,
+            num_aux: self.num_aux,
+            num_constraints: self.constraints.len(),
+            hash: hex::encode(hasher.finalize()),
+        }
+    }
+}
+
+type Lc = Vec<(Index, BellmanFr)>;
+
+// Records the constraints of a circuit without computing any assignments, so
+// that an unfilled (Default) circuit can be synthesized.
+pub struct RecordingCs {
+    namespace: Vec<String>,
+    inputs: Vec<String>,
+    aux: Vec<String>,
+    constraints: Vec<(String, Lc, Lc, Lc)>,
+}
+
+impl Default for RecordingCs {
+    fn default() -> Self {
+        Self {
+            namespace: Vec::new(),
+            inputs: vec!["ONE".into()],
+            aux: Vec::new(),
+            constraints: Vec::new(),
+        }
+    }
+}
+
+impl RecordingCs {
+    fn path(&self, name: String) -> String {
+        let mut parts = self.namespace.clone();
+        if !name.is_empty() {
+            parts.push(name);
+        }
+        parts.join("/")
+    }
+
+    fn terms(&self, lc: &Lc) -> Vec<Term> {
+        let mut merged = BTreeMap::<usize, BellmanFr>::new();
+        for (index, coeff) in lc.iter() {
+            let wire = match index {
+                Index::Input(i) => *i,
+                Index::Aux(i) => self.inputs.len() + i,
+            };
+            *merged.entry(wire).or_insert_with(BellmanFr::zero) += coeff;
+        }
+        merged
+            .into_iter()
+            .filter(|(_, coeff)| !bool::from(coeff.is_zero()))
+            .map(|(wire, coeff)| Term {
+                wire,
+                coeff: encode_scalar(&coeff),
+            })
+            .collect()
+    }
+
+    pub fn into_r1cs(self, circuit: &str) -> R1cs {
+        let constraints = self
+            .constraints
+            .iter()
+            .enumerate()
+            .map(|(i, (name, a, b, c))| Constraint {
+                name: if name.is_empty() {
+                    format!("constraint {}", i)
+                } else {
+                    name.clone()
+                },
+                a: self.terms(a),
+                b: self.terms(b),
+                c: self.terms(c),
+            })
+            .collect();
+        R1cs {
+            version: R1CS_VERSION,
+            circuit: circuit.into(),
+            field_modulus: FIELD_MODULUS.into(),
+            num_inputs: self.inputs.len(),
+            num_aux: self.aux.len(),
+            wires: self.inputs.iter().chain(self.aux.iter()).cloned().collect(),
+            constraints,
+        }
+    }
+}
+
+fn record(lc: LinearCombination<BellmanFr>) -> Lc {
+    lc.as_ref()
+        .iter()
+        .map(|(v, coeff)| (v.get_unchecked(), *coeff))
+        .collect()
+}
+
+impl ConstraintSystem<BellmanFr> for RecordingCs {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(&mut self, annotation: A, _f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        let name = self.path(annotation().into());
+        self.aux.push(name);
+        Ok(Variable::new_unchecked(Index::Aux(self.aux.len() - 1)))
+    }
+
+    fn alloc_input<F, A, AR>(&mut self, annotation: A, _f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        let name = self.path(annotation().into());
+        self.inputs.push(name);
+        Ok(Variable::new_unchecked(Index::Input(self.inputs.len() - 1)))
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(&mut self, annotation: A, a: LA, b: LB, c: LC)
+    where
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+        LA: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LB: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LC: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+    {
+        let name = self.path(annotation().into());
+        let a = record(a(LinearCombination::zero()));
+        let b = record(b(LinearCombination::zero()));
+        let c = record(c(LinearCombination::zero()));
+        self.constraints.push((name, a, b, c));
+    }
+
+    fn push_namespace<NR, N>(&mut self, name_fn: N)
+    where
+        NR: Into<String>,
+        N: FnOnce() -> NR,
+    {
+        self.namespace.push(name_fn().into());
+    }
+
+    fn pop_namespace(&mut self) {
+        self.namespace.pop();
+    }
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
+
+pub fn export<C: Circuit<BellmanFr> + Default>(name: &str) -> Result<R1cs, SynthesisError> {
+    let mut cs = RecordingCs::default();
+    C::default().synthesize(&mut cs)?;
+    Ok(cs.into_r1cs(name))
+}
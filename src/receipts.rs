@@ -0,0 +1,132 @@
+use bazuka::zk::ZkScalar;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ReceiptError {
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("json error: {0}")]
+    JsonError(#[from] serde_json::Error),
+}
+
+// Mempool items are identified by their serialized form
+pub fn item_id<T: Serialize>(item: &T) -> Vec<u8> {
+    bincode::serialize(item).unwrap()
+}
+
+// Identifies a transaction or deposit/withdraw in the receipts and the RPC.
+// It's the SHA-256 of the serialized item, not the hash bazuka computes for
+// the transaction.
+pub fn receipt_id<T: Serialize>(item: &T) -> String {
+    hex::encode(Sha256::digest(&item_id(item)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReceiptStatus {
+    // The batch is sent to the node
+    Submitted,
+    // The batch is applied on the chain
+    Applied,
+    // The chain has diverged before applying the batch, the transaction will
+    // be included in a later batch if it's still valid
+    Dropped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Receipt {
+    pub batch: u64,
+    pub state: ZkScalar,
+    pub next_state: ZkScalar,
+    pub l1_tx: String,
+    pub status: ReceiptStatus,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Receipts {
+    next_batch: u64,
+    batches: HashMap<u64, Vec<String>>,
+    receipts: HashMap<String, Receipt>,
+}
+
+// Maps the receipt id of each transaction to the batch including it. The
+// store is a JSON file, changes are kept in memory until `flush` is called.
+pub struct ReceiptStore {
+    path: PathBuf,
+    data: Receipts,
+    dirty: bool,
+}
+
+impl ReceiptStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ReceiptError> {
+        let path = path.as_ref().to_path_buf();
+        let data = if path.exists() {
+            serde_json::from_reader(File::open(&path)?)?
+        } else {
+            Receipts::default()
+        };
+        Ok(Self {
+            path,
+            data,
+            dirty: false,
+        })
+    }
+
+    // Writes the changes since the last flush, if any
+    pub fn flush(&mut self) -> Result<(), ReceiptError> {
+        if self.dirty {
+            let tmp = self.path.with_extension("tmp");
+            serde_json::to_writer(File::create(&tmp)?, &self.data)?;
+            std::fs::rename(tmp, &self.path)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Receipt> {
+        self.data.receipts.get(id)
+    }
+
+    // Records a submitted batch, returns its id
+    pub fn submitted(
+        &mut self,
+        ids: Vec<String>,
+        state: ZkScalar,
+        next_state: ZkScalar,
+        l1_tx: String,
+    ) -> u64 {
+        let batch = self.data.next_batch;
+        self.data.next_batch += 1;
+        for id in ids.iter() {
+            self.data.receipts.insert(
+                id.clone(),
+                Receipt {
+                    batch,
+                    state,
+                    next_state,
+                    l1_tx: l1_tx.clone(),
+                    status: ReceiptStatus::Submitted,
+                },
+            );
+        }
+        self.data.batches.insert(batch, ids);
+        self.dirty = true;
+        batch
+    }
+
+    pub fn set_status(&mut self, batch: u64, status: ReceiptStatus) {
+        for id in self.data.batches.get(&batch).cloned().unwrap_or_default() {
+            if let Some(r) = self.data.receipts.get_mut(&id) {
+                // A later batch might have included the transaction again
+                if r.batch == batch {
+                    r.status = status;
+                }
+            }
+        }
+        self.dirty = true;
+    }
+}
@@ -0,0 +1,130 @@
+use crate::config::LOG4_TREE_SIZE;
+use crate::state::{get_account, StateError, StateModelVersion};
+use bazuka::core::ZkHasher;
+use bazuka::crypto::jubjub::{PointAffine, PublicKey};
+use bazuka::zk::{KvStoreStateManager, ZkDataLocator, ZkScalar};
+use bazuka::{config::blockchain::MPN_CONTRACT_ID, db::KvStore};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::Mutex;
+
+// Registries of the most recent states, built incrementally from the registry
+// of the state they were built on.
+const CACHED_REGISTRIES: usize = 16;
+
+lazy_static! {
+    static ref REGISTRIES: Mutex<VecDeque<(ZkScalar, Registry)>> = Mutex::new(VecDeque::new());
+}
+
+fn key(address: &PointAffine) -> Vec<u8> {
+    bincode::serialize(&PublicKey(address.compress())).unwrap()
+}
+
+// Maps the public key of each MPN account to its index
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    indices: HashMap<Vec<u8>, u32>,
+    keys: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Registry {
+    pub fn from_state<K: KvStore>(db: &K, version: StateModelVersion) -> Result<Self, StateError> {
+        let full_state = KvStoreStateManager::<ZkHasher>::get_full_state(db, *MPN_CONTRACT_ID)?;
+        let mut registry = Self::default();
+        for index in full_state
+            .data
+            .0
+            .keys()
+            .map(|loc| loc.0[0])
+            .collect::<BTreeSet<u32>>()
+        {
+            let acc = get_account(db, version, index)?;
+            if acc.address != Default::default() {
+                registry.register(index, &acc.address);
+            }
+        }
+        Ok(registry)
+    }
+
+    // The registry of a recent state is reused, only unknown states are scanned
+    pub fn for_state<K: KvStore>(db: &K, version: StateModelVersion) -> Result<Self, StateError> {
+        let root = KvStoreStateManager::<ZkHasher>::get_data(
+            db,
+            *MPN_CONTRACT_ID,
+            &ZkDataLocator(vec![]),
+        )?;
+        if let Some((_, registry)) = REGISTRIES.lock().unwrap().iter().find(|(r, _)| *r == root) {
+            return Ok(registry.clone());
+        }
+        let registry = Self::from_state(db, version)?;
+        registry.remember(root);
+        Ok(registry)
+    }
+
+    // Caches the registry as the registry of the state with the given root
+    pub fn remember(&self, root: ZkScalar) {
+        let mut registries = REGISTRIES.lock().unwrap();
+        if registries.iter().any(|(r, _)| *r == root) {
+            return;
+        }
+        if registries.len() >= CACHED_REGISTRIES {
+            registries.pop_front();
+        }
+        registries.push_back((root, self.clone()));
+    }
+
+    // The previous public key of the index is released
+    pub fn register(&mut self, index: u32, address: &PointAffine) {
+        let key = key(address);
+        if let Some(old) = self.keys.insert(index, key.clone()) {
+            if old != key && self.indices.get(&old) == Some(&index) {
+                self.indices.remove(&old);
+            }
+        }
+        self.indices.insert(key, index);
+    }
+
+    pub fn get(&self, pub_key: &PublicKey) -> Option<u32> {
+        self.indices.get(&key(&pub_key.0.decompress())).cloned()
+    }
+
+    // True if the public key is registered in a slot other than `index`
+    pub fn is_taken(&self, index: u32, address: &PointAffine) -> bool {
+        self.indices
+            .get(&key(address))
+            .map(|i| *i != index)
+            .unwrap_or(false)
+    }
+
+    pub fn next_free(&self) -> Option<u32> {
+        let capacity = 1u64 << (2 * LOG4_TREE_SIZE as u64);
+        (0..capacity)
+            .map(|i| i as u32)
+            .find(|i| !self.keys.contains_key(i))
+    }
+
+    // Index of the account of the public key, or the next free index if the
+    // public key has no account yet.
+    pub fn allocate(&self, pub_key: &PublicKey) -> Option<u32> {
+        self.get(pub_key).or_else(|| self.next_free())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bazuka::crypto::{jubjub, ZkSignatureScheme};
+
+    #[test]
+    fn test_register_releases_old_key() {
+        let (alice, _) = jubjub::JubJub::<ZkHasher>::generate_keys(b"alice");
+        let (bob, _) = jubjub::JubJub::<ZkHasher>::generate_keys(b"bob");
+        let mut registry = Registry::default();
+        registry.register(3, &alice.0.decompress());
+        registry.register(3, &bob.0.decompress());
+        assert_eq!(registry.get(&alice), None);
+        assert_eq!(registry.get(&bob), Some(3));
+        assert!(!registry.is_taken(5, &alice.0.decompress()));
+        assert!(registry.is_taken(5, &bob.0.decompress()));
+        assert_eq!(registry.next_free(), Some(0));
+    }
+}
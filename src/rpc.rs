@@ -0,0 +1,248 @@
+use crate::bank::{BankError, StateOverlay};
+use crate::executor::to_deposit_withdraw;
+use crate::metrics;
+use crate::receipts::{item_id, receipt_id, ReceiptStore};
+use crate::state::CHAIN_STATE_MODEL_VERSION;
+use bazuka::config::blockchain::MPN_CONTRACT_ID;
+use bazuka::core::ContractPayment;
+use bazuka::db::{KvStore, RamMirrorKvStore, ReadOnlyLevelDbKvStore, WriteOp};
+use bazuka::zk::ZeroTransaction;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+// JSON-RPC 2.0 over HTTP (POST /). Methods:
+//
+// - submit_transaction: params is a signed `ZeroTransaction`
+// - submit_deposit_withdraw: params is a `ContractPayment` of the MPN contract
+// - get_receipt: params is the receipt id of a transaction, the result is its
+//   `Receipt`, or null if it's not included in any batch yet
+// - get_receipts: params is an array of receipt ids, the result is an array of
+//   their receipts (Or nulls), in the same order
+//
+// Transactions are checked against the state predicted by the executor (After
+// the batches it has submitted or is proving) and the queued transactions, the
+// same way batches are checked. On success, the result is the receipt id of the
+// transaction, to be used with get_receipt. Receipt ids are specific to zoro,
+// they are not the hashes bazuka gives to the transactions. Rejected
+// transactions get an error with code -32000 and the reason as the message.
+
+const PARSE_ERROR: i32 = -32700;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const REJECTED: i32 = -32000;
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+// Transactions accepted through the RPC, waiting to be included in a batch
+#[derive(Debug, Default)]
+pub struct TxQueue {
+    pub deposit_withdraws: Vec<ContractPayment>,
+    pub transactions: Vec<ZeroTransaction>,
+    // Writes of the batches which are submitted or being proven but not
+    // applied on the chain yet, published by the executor
+    pub pending: Vec<WriteOp>,
+}
+
+impl TxQueue {
+    // Forgets the transactions which are applied on the chain
+    pub fn remove(&mut self, items: &HashSet<Vec<u8>>) {
+        self.deposit_withdraws
+            .retain(|dw| !items.contains(&item_id(dw)));
+        self.transactions.retain(|tx| !items.contains(&item_id(tx)));
+    }
+
+    // The state the executor builds its next batches on. The pending writes
+    // hold whole values, so writes already applied on the chain are harmless.
+    pub fn predict<'a, K: KvStore>(&self, db: &'a K) -> RamMirrorKvStore<'a, K> {
+        let mut predicted = db.mirror();
+        predicted.update(&self.pending).unwrap();
+        predicted
+    }
+}
+
+// The state after the queued deposit/withdraws and, if `transactions` is set,
+// the queued transactions. Queued items which no longer apply (e.g. when the
+// state has changed on the chain) are removed from the queue.
+fn replay<'a, K: KvStore>(
+    db: &'a K,
+    queue: &mut TxQueue,
+    transactions: bool,
+) -> Result<StateOverlay<'a, K>, BankError> {
+    let mut overlay = StateOverlay::new(db, CHAIN_STATE_MODEL_VERSION)?;
+    let mut rejected = HashSet::new();
+    for dw in queue.deposit_withdraws.iter() {
+        if let Err(e) = overlay.apply_deposit_withdraw(&to_deposit_withdraw(dw)) {
+            warn!(tx = %receipt_id(dw), error = ?e, "Queued deposit/withdraw dropped");
+            rejected.insert(item_id(dw));
+        }
+    }
+    if transactions {
+        for tx in queue.transactions.iter() {
+            if let Err(e) = overlay.apply_transfer(tx) {
+                warn!(tx = %receipt_id(tx), error = ?e, "Queued transaction dropped");
+                rejected.insert(item_id(tx));
+            }
+        }
+    }
+    queue.remove(&rejected);
+    Ok(overlay)
+}
+
+pub struct RpcServer {
+    queue: Arc<Mutex<TxQueue>>,
+    receipts: Arc<Mutex<ReceiptStore>>,
+    db_shutter: ReadOnlyLevelDbKvStore,
+}
+
+impl RpcServer {
+    pub fn new(
+        queue: Arc<Mutex<TxQueue>>,
+        receipts: Arc<Mutex<ReceiptStore>>,
+        db_shutter: ReadOnlyLevelDbKvStore,
+    ) -> Self {
+        Self {
+            queue,
+            receipts,
+            db_shutter,
+        }
+    }
+
+    fn submit_transaction(&self, tx: ZeroTransaction) -> Result<String, String> {
+        let mut queue = self.queue.lock().unwrap();
+        let id = item_id(&tx);
+        if queue.transactions.iter().any(|q| item_id(q) == id) {
+            return Err("Already queued!".into());
+        }
+        let db = self.db_shutter.snapshot();
+        let predicted = queue.predict(&db);
+        replay(&predicted, &mut queue, true)
+            .and_then(|mut overlay| overlay.apply_transfer(&tx))
+            .map_err(|e| {
+                metrics::bank_error(&e);
+                format!("{:?}", e)
+            })?;
+        let id = receipt_id(&tx);
+        queue.transactions.push(tx);
+        Ok(id)
+    }
+
+    fn submit_deposit_withdraw(&self, dw: ContractPayment) -> Result<String, String> {
+        if dw.contract_id != *MPN_CONTRACT_ID {
+            return Err("Not a payment of the MPN contract!".into());
+        }
+        let mut queue = self.queue.lock().unwrap();
+        let id = item_id(&dw);
+        if queue.deposit_withdraws.iter().any(|q| item_id(q) == id) {
+            return Err("Already queued!".into());
+        }
+        let db = self.db_shutter.snapshot();
+        let predicted = queue.predict(&db);
+        replay(&predicted, &mut queue, false)
+            .and_then(|mut overlay| overlay.apply_deposit_withdraw(&to_deposit_withdraw(&dw)))
+            .map_err(|e| {
+                metrics::bank_error(&e);
+                format!("{:?}", e)
+            })?;
+        let id = receipt_id(&dw);
+        queue.deposit_withdraws.push(dw);
+        Ok(id)
+    }
+
+    fn handle(&self, body: &str) -> RpcResponse {
+        let mut resp = RpcResponse {
+            jsonrpc: "2.0",
+            id: Value::Null,
+            result: None,
+            error: None,
+        };
+        let req = match serde_json::from_str::<RpcRequest>(body) {
+            Ok(req) => req,
+            Err(e) => {
+                resp.error = Some(RpcError {
+                    code: PARSE_ERROR,
+                    message: e.to_string(),
+                });
+                return resp;
+            }
+        };
+        resp.id = req.id;
+        let res = match req.method.as_str() {
+            "submit_transaction" => serde_json::from_value(req.params)
+                .map_err(|e| (INVALID_PARAMS, e.to_string()))
+                .and_then(|tx| self.submit_transaction(tx).map_err(|e| (REJECTED, e)))
+                .map(Value::from),
+            "submit_deposit_withdraw" => serde_json::from_value(req.params)
+                .map_err(|e| (INVALID_PARAMS, e.to_string()))
+                .and_then(|dw| self.submit_deposit_withdraw(dw).map_err(|e| (REJECTED, e)))
+                .map(Value::from),
+            "get_receipt" => serde_json::from_value::<String>(req.params)
+                .map_err(|e| (INVALID_PARAMS, e.to_string()))
+                .map(|hash| {
+                    serde_json::to_value(self.receipts.lock().unwrap().get(&hash)).unwrap()
+                }),
+            "get_receipts" => serde_json::from_value::<Vec<String>>(req.params)
+                .map_err(|e| (INVALID_PARAMS, e.to_string()))
+                .map(|hashes| {
+                    let receipts = self.receipts.lock().unwrap();
+                    let receipts = hashes.iter().map(|h| receipts.get(h)).collect::<Vec<_>>();
+                    serde_json::to_value(receipts).unwrap()
+                }),
+            _ => Err((METHOD_NOT_FOUND, "Method not found!".into())),
+        };
+        match res {
+            Ok(result) => resp.result = Some(result),
+            Err((code, message)) => resp.error = Some(RpcError { code, message }),
+        }
+        resp
+    }
+
+    pub fn serve(&self, addr: &str) -> Result<(), std::io::Error> {
+        let server = tiny_http::Server::http(addr)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        info!(addr, "RPC listening");
+        for mut request in server.incoming_requests() {
+            let response = if request.method() != &tiny_http::Method::Post || request.url() != "/" {
+                tiny_http::Response::from_string("Not found!").with_status_code(404)
+            } else {
+                let mut body = String::new();
+                match request.as_reader().read_to_string(&mut body) {
+                    Ok(_) => tiny_http::Response::from_string(
+                        serde_json::to_string(&self.handle(&body)).unwrap(),
+                    ),
+                    Err(e) => tiny_http::Response::from_string(e.to_string()).with_status_code(400),
+                }
+            };
+            if let Err(e) = request.respond(response) {
+                warn!(error = %e, "Unable to respond");
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,534 @@
+use crate::config::{LOG4_TOKENS_TREE_SIZE, LOG4_TREE_SIZE, TOKENS_PER_ACCOUNT};
+use crate::core;
+use bazuka::zk::ZkScalar;
+use bazuka::{
+    blockchain::{Blockchain, KvStoreChain},
+    config::blockchain::{get_blockchain_config, MPN_CONTRACT_ID},
+    core::ZkHasher,
+    crypto::jubjub::PointAffine,
+    db::{keys, KvStore, RamKvStore, WriteOp},
+    zk::{
+        KvStoreStateManager, StateManagerError, ZkCompressedState, ZkDataLocator, ZkDeltaPairs,
+        ZkStateModel,
+    },
+};
+use thiserror::Error;
+
+// Defines the fields of a struct in the MPN state model. The locator of each
+// field is derived from its position, so fields should only be appended, in a
+// new state model version.
+macro_rules! state_struct {
+    ($name:ident { $($field:ident => $model:expr),* $(,)? }) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u32)]
+        pub enum $name {
+            $($field),*
+        }
+
+        impl $name {
+            #[allow(dead_code)]
+            pub const ALL: &'static [$name] = &[$($name::$field),*];
+
+            #[allow(dead_code)]
+            pub fn at(self, prefix: &[u32]) -> ZkDataLocator {
+                let mut locator = prefix.to_vec();
+                locator.push(self as u32);
+                ZkDataLocator(locator)
+            }
+
+            pub fn model() -> ZkStateModel {
+                ZkStateModel::Struct {
+                    field_types: vec![$($model),*],
+                }
+            }
+        }
+    };
+}
+
+// Version 1: The model of the MPN contract on the chain
+state_struct!(AccountField {
+    Nonce => ZkStateModel::Scalar,
+    PubKeyX => ZkStateModel::Scalar,
+    PubKeyY => ZkStateModel::Scalar,
+    Balance => ZkStateModel::Scalar,
+});
+
+// Version 2: Accounts with token-balance subtrees, appended to the fields of
+// version 1
+state_struct!(TokenField {
+    TokenId => ZkStateModel::Scalar,
+    Amount => ZkStateModel::Scalar,
+});
+state_struct!(TokenAccountField {
+    Nonce => ZkStateModel::Scalar,
+    PubKeyX => ZkStateModel::Scalar,
+    PubKeyY => ZkStateModel::Scalar,
+    Balance => ZkStateModel::Scalar,
+    Tokens => ZkStateModel::List {
+        log4_size: LOG4_TOKENS_TREE_SIZE,
+        item_type: Box::new(TokenField::model()),
+    },
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateModelVersion {
+    V1,
+    V2,
+}
+
+impl StateModelVersion {
+    pub fn from_number(num: u32) -> Option<Self> {
+        match num {
+            1 => Some(StateModelVersion::V1),
+            2 => Some(StateModelVersion::V2),
+            _ => None,
+        }
+    }
+    pub fn number(&self) -> u32 {
+        match self {
+            StateModelVersion::V1 => 1,
+            StateModelVersion::V2 => 2,
+        }
+    }
+    pub fn next(&self) -> Option<Self> {
+        Self::from_number(self.number() + 1)
+    }
+    // Token slots of each account, version 1 accounts have none
+    pub fn tokens_per_account(&self) -> usize {
+        match self {
+            StateModelVersion::V1 => 0,
+            StateModelVersion::V2 => TOKENS_PER_ACCOUNT,
+        }
+    }
+    pub fn model(&self) -> ZkStateModel {
+        ZkStateModel::List {
+            log4_size: LOG4_TREE_SIZE,
+            item_type: Box::new(match self {
+                StateModelVersion::V1 => AccountField::model(),
+                StateModelVersion::V2 => TokenAccountField::model(),
+            }),
+        }
+    }
+}
+
+// The MPN contract on the chain has this model, the update and
+// deposit/withdraw circuits hash the accounts accordingly.
+pub const CHAIN_STATE_MODEL_VERSION: StateModelVersion = StateModelVersion::V1;
+
+// The latest model, the unified MPN circuit hashes the accounts accordingly.
+pub const STATE_MODEL_VERSION: StateModelVersion = StateModelVersion::V2;
+
+#[derive(Error, Debug)]
+pub enum StateError {
+    #[error("account {0} has tokens, which state model version {1} lacks")]
+    TokensNotSupported(u32, u32),
+    #[error("account {0} has invalid data")]
+    InvalidAccount(u32),
+    #[error("state manager error: {0}")]
+    StateManagerError(#[from] StateManagerError),
+}
+
+// A fresh in-memory database, holding the genesis state of the chain and an
+// empty MPN state of the given model version. The chain creates the MPN
+// contract with the model of `CHAIN_STATE_MODEL_VERSION`, for other versions
+// the model of the (Still empty) contract is replaced.
+pub fn genesis_db(version: StateModelVersion) -> RamKvStore {
+    let chain = KvStoreChain::new(RamKvStore::new(), get_blockchain_config()).unwrap();
+    let mut db = chain.database().clone();
+    if version != CHAIN_STATE_MODEL_VERSION {
+        let empty = ZkCompressedState::empty::<ZkHasher>(version.model());
+        let mut contract = chain.get_contract(*MPN_CONTRACT_ID).unwrap();
+        contract.state_model = version.model();
+        contract.initial_state = empty.clone();
+        db.update(&[
+            WriteOp::Put(keys::contract(&MPN_CONTRACT_ID), contract.into()),
+            WriteOp::Put(keys::local_root(&MPN_CONTRACT_ID), empty.into()),
+        ])
+        .unwrap();
+    }
+    db
+}
+
+fn get_data<K: KvStore>(db: &K, locator: ZkDataLocator) -> Result<ZkScalar, StateError> {
+    Ok(KvStoreStateManager::<ZkHasher>::get_data(
+        db,
+        *MPN_CONTRACT_ID,
+        &locator,
+    )?)
+}
+
+fn get_u64<K: KvStore>(db: &K, index: u32, locator: ZkDataLocator) -> Result<u64, StateError> {
+    get_data(db, locator)?
+        .try_into()
+        .map_err(|_| StateError::InvalidAccount(index))
+}
+
+fn token_prefix(index: u32, token_index: u32) -> [u32; 3] {
+    [index, TokenAccountField::Tokens as u32, token_index]
+}
+
+// The fields of version 1 keep their locators in later versions
+pub fn get_account<K: KvStore>(
+    db: &K,
+    version: StateModelVersion,
+    index: u32,
+) -> Result<core::Account, StateError> {
+    let mut acc = core::Account {
+        nonce: get_u64(db, index, AccountField::Nonce.at(&[index]))?,
+        address: PointAffine(
+            get_data(db, AccountField::PubKeyX.at(&[index]))?,
+            get_data(db, AccountField::PubKeyY.at(&[index]))?,
+        ),
+        balance: get_u64(db, index, AccountField::Balance.at(&[index]))?,
+        tokens: Default::default(),
+    };
+    for (token_index, token) in acc
+        .tokens
+        .iter_mut()
+        .take(version.tokens_per_account())
+        .enumerate()
+    {
+        let prefix = token_prefix(index, token_index as u32);
+        token.token_id = get_data(db, TokenField::TokenId.at(&prefix))?;
+        token.amount = get_u64(db, index, TokenField::Amount.at(&prefix))?;
+    }
+    Ok(acc)
+}
+
+// Locators and values of the scalar fields of an account. Accounts with tokens
+// the model has no slot for are rejected.
+fn account_fields(
+    version: StateModelVersion,
+    index: u32,
+    acc: &core::Account,
+) -> Result<Vec<(ZkDataLocator, ZkScalar)>, StateError> {
+    if acc
+        .tokens
+        .iter()
+        .skip(version.tokens_per_account())
+        .any(|t| *t != core::Token::default())
+    {
+        return Err(StateError::TokensNotSupported(index, version.number()));
+    }
+    let mut fields = vec![
+        (AccountField::Nonce.at(&[index]), ZkScalar::from(acc.nonce)),
+        (AccountField::PubKeyX.at(&[index]), acc.address.0),
+        (AccountField::PubKeyY.at(&[index]), acc.address.1),
+        (
+            AccountField::Balance.at(&[index]),
+            ZkScalar::from(acc.balance),
+        ),
+    ];
+    for (token_index, token) in acc
+        .tokens
+        .iter()
+        .take(version.tokens_per_account())
+        .enumerate()
+    {
+        let prefix = token_prefix(index, token_index as u32);
+        fields.push((TokenField::TokenId.at(&prefix), token.token_id));
+        fields.push((TokenField::Amount.at(&prefix), ZkScalar::from(token.amount)));
+    }
+    Ok(fields)
+}
+
+// Checks that the account fits in the state model, without writing it
+pub fn check_account(
+    version: StateModelVersion,
+    index: u32,
+    acc: &core::Account,
+) -> Result<(), StateError> {
+    account_fields(version, index, acc).map(|_| ())
+}
+
+pub fn set_account<K: KvStore>(
+    db: &mut K,
+    version: StateModelVersion,
+    index: u32,
+    acc: core::Account,
+) -> Result<(), StateError> {
+    for (locator, value) in account_fields(version, index, &acc)? {
+        KvStoreStateManager::<ZkHasher>::set_data(db, *MPN_CONTRACT_ID, locator, value)?;
+    }
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum DeltaError {
+    #[error("locator {0:?} is not an account field")]
+    InvalidLocator(ZkDataLocator),
+    #[error("state manager error: {0}")]
+    StateManagerError(#[from] StateManagerError),
+}
+
+pub fn is_account_locator(version: StateModelVersion, locator: &ZkDataLocator) -> bool {
+    match locator.0.as_slice() {
+        [index, fields @ ..] if (*index as u64) < 1 << (2 * LOG4_TREE_SIZE as u64) => {
+            match fields {
+                [field] => AccountField::ALL.iter().any(|f| *f as u32 == *field),
+                [tokens, token_index, field] => {
+                    *tokens == TokenAccountField::Tokens as u32
+                        && (*token_index as usize) < version.tokens_per_account()
+                        && TokenField::ALL.iter().any(|f| *f as u32 == *field)
+                }
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+// Data pairs of the given accounts. Zero fields are not stored by the state
+// manager, so they are removed.
+pub fn account_delta<'a, I: IntoIterator<Item = (u32, &'a core::Account)>>(
+    version: StateModelVersion,
+    accounts: I,
+) -> Result<ZkDeltaPairs, StateError> {
+    let mut pairs = ZkDeltaPairs([].into());
+    for (index, acc) in accounts {
+        for (locator, value) in account_fields(version, index, acc)? {
+            pairs
+                .0
+                .insert(locator, (value != ZkScalar::from(0)).then(|| value));
+        }
+    }
+    Ok(pairs)
+}
+
+// Applies a delta of account fields on the state, rejecting locators that are
+// not in the state model.
+pub fn apply_delta<K: KvStore>(
+    db: &mut K,
+    version: StateModelVersion,
+    delta: &ZkDeltaPairs,
+) -> Result<(), DeltaError> {
+    for (locator, value) in delta.0.iter() {
+        if !is_account_locator(version, locator) {
+            return Err(DeltaError::InvalidLocator(locator.clone()));
+        }
+        KvStoreStateManager::<ZkHasher>::set_data(
+            db,
+            *MPN_CONTRACT_ID,
+            locator.clone(),
+            value.unwrap_or_else(|| ZkScalar::from(0)),
+        )?;
+    }
+    Ok(())
+}
+
+// Number of non-empty scalars in the state after applying the delta, the same
+// way the chain counts it.
+pub fn state_size_after<K: KvStore>(db: &K, delta: &ZkDeltaPairs) -> Result<u32, StateError> {
+    let mut size = KvStoreStateManager::<ZkHasher>::root(db, *MPN_CONTRACT_ID)?.state_size as i64;
+    for (locator, value) in delta.0.iter() {
+        let before = get_data(db, locator.clone())? != ZkScalar::from(0);
+        size += value.is_some() as i64 - before as i64;
+    }
+    Ok(size as u32)
+}
+
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    #[error("unknown state model version {0}")]
+    UnknownVersion(u32),
+    #[error("no migration from state model version {0}")]
+    NoMigration(u32),
+    #[error("locator {0:?} is not in the state model")]
+    InvalidLocator(ZkDataLocator),
+    #[error("state manager error: {0}")]
+    StateManagerError(#[from] StateManagerError),
+    #[error("delta error: {0}")]
+    DeltaError(#[from] DeltaError),
+}
+
+// Maps a full MPN state to the next state model version, returning the data of
+// the migrated state and its root, as computed by the state manager on a fresh
+// state of the next version.
+pub fn migrate<K: KvStore>(
+    db: &K,
+    from: StateModelVersion,
+) -> Result<(ZkDeltaPairs, ZkCompressedState), MigrationError> {
+    let to = from
+        .next()
+        .ok_or_else(|| MigrationError::NoMigration(from.number()))?;
+    let full_state = KvStoreStateManager::<ZkHasher>::get_full_state(db, *MPN_CONTRACT_ID)?;
+    if let Some(locator) = full_state
+        .data
+        .0
+        .keys()
+        .find(|l| !is_account_locator(from, l))
+    {
+        return Err(MigrationError::InvalidLocator(locator.clone()));
+    }
+    // Versions only append fields, so the fields keep their locators and the
+    // new ones (The token subtrees of version 2) start empty.
+    let data = ZkDeltaPairs(
+        full_state
+            .data
+            .0
+            .into_iter()
+            .map(|(locator, value)| (locator, Some(value)))
+            .collect(),
+    );
+    let mut migrated = genesis_db(to);
+    apply_delta(&mut migrated, to, &data)?;
+    let root = KvStoreStateManager::<ZkHasher>::root(&migrated, *MPN_CONTRACT_ID)?;
+    Ok((data, root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::hash_account;
+    use crate::tree::SparseTree;
+    use bazuka::crypto::{jubjub, ZkSignatureScheme};
+
+    fn accounts() -> Vec<(u32, core::Account)> {
+        (0..5u32)
+            .map(|i| {
+                let (pub_key, _) =
+                    jubjub::JubJub::<ZkHasher>::generate_keys(format!("user-{}", i).as_bytes());
+                let acc = core::Account {
+                    nonce: i as u64 + 1,
+                    address: pub_key.0.decompress(),
+                    balance: 1000 + i as u64,
+                    tokens: Default::default(),
+                };
+                (i * 3, acc)
+            })
+            .collect()
+    }
+
+    fn root<K: KvStore>(db: &K) -> ZkCompressedState {
+        KvStoreStateManager::<ZkHasher>::root(db, *MPN_CONTRACT_ID).unwrap()
+    }
+
+    #[test]
+    fn test_account_delta() {
+        let before = accounts();
+        let mut after = before.clone();
+        after[0].1.balance = 0;
+        after[1].1.nonce += 1;
+        after[2].1.tokens[1] = core::Token {
+            token_id: ZkScalar::from(7),
+            amount: 500,
+        };
+        after.push((100, after[3].1.clone()));
+
+        let mut db = genesis_db(STATE_MODEL_VERSION);
+        for (index, acc) in before.iter() {
+            set_account(&mut db, STATE_MODEL_VERSION, *index, acc.clone()).unwrap();
+        }
+        let mut expected = genesis_db(STATE_MODEL_VERSION);
+        for (index, acc) in after.iter() {
+            set_account(&mut expected, STATE_MODEL_VERSION, *index, acc.clone()).unwrap();
+        }
+
+        let delta =
+            account_delta(STATE_MODEL_VERSION, after.iter().map(|(i, acc)| (*i, acc))).unwrap();
+        let mut mirror = db.mirror();
+        apply_delta(&mut mirror, STATE_MODEL_VERSION, &delta).unwrap();
+        assert_eq!(root(&mirror).state_hash, root(&expected).state_hash);
+        assert_eq!(root(&mirror).state_size, root(&expected).state_size);
+        assert_eq!(
+            state_size_after(&db, &delta).unwrap(),
+            root(&expected).state_size
+        );
+        for (index, acc) in after.iter() {
+            assert_eq!(
+                get_account(&mirror, STATE_MODEL_VERSION, *index).unwrap(),
+                *acc
+            );
+        }
+    }
+
+    #[test]
+    fn test_tokens_not_supported() {
+        let mut acc = accounts()[0].1.clone();
+        acc.tokens[0] = core::Token {
+            token_id: ZkScalar::from(7),
+            amount: 500,
+        };
+        let mut db = genesis_db(StateModelVersion::V1);
+        let root_before = root(&db);
+        assert!(matches!(
+            set_account(&mut db, StateModelVersion::V1, 3, acc.clone()),
+            Err(StateError::TokensNotSupported(3, 1))
+        ));
+        assert!(matches!(
+            account_delta(StateModelVersion::V1, [(3, &acc)]),
+            Err(StateError::TokensNotSupported(3, 1))
+        ));
+        assert_eq!(root(&db).state_hash, root_before.state_hash);
+    }
+
+    #[test]
+    fn test_apply_delta_rejects_locators() {
+        let nonce = TokenAccountField::Nonce as u32;
+        let tokens = TokenAccountField::Tokens as u32;
+        let token_id = TokenField::TokenId as u32;
+        let invalid = vec![
+            vec![],
+            vec![0],
+            vec![0, tokens],
+            vec![0, TokenAccountField::ALL.len() as u32],
+            vec![0, nonce, 0],
+            vec![0, tokens, TOKENS_PER_ACCOUNT as u32, token_id],
+            vec![0, tokens, 0, TokenField::ALL.len() as u32],
+            vec![0, tokens, 0, token_id, 0],
+            vec![1 << (2 * LOG4_TREE_SIZE), nonce],
+        ];
+        let mut db = genesis_db(STATE_MODEL_VERSION);
+        let root_before = root(&db);
+        for locator in invalid {
+            let delta =
+                ZkDeltaPairs([(ZkDataLocator(locator.clone()), Some(ZkScalar::from(1)))].into());
+            assert!(
+                matches!(
+                    apply_delta(&mut db, STATE_MODEL_VERSION, &delta),
+                    Err(DeltaError::InvalidLocator(_))
+                ),
+                "Locator {:?} accepted!",
+                locator
+            );
+        }
+        assert_eq!(root(&db).state_hash, root_before.state_hash);
+    }
+
+    #[test]
+    fn test_migrated_root() {
+        let accounts = accounts();
+        let mut db = genesis_db(StateModelVersion::V1);
+        for (index, acc) in accounts.iter() {
+            set_account(&mut db, StateModelVersion::V1, *index, acc.clone()).unwrap();
+        }
+        let (data, root) = migrate(&db, StateModelVersion::V1).unwrap();
+        assert_eq!(data.0.len(), accounts.len() * AccountField::ALL.len());
+
+        let mut tree = SparseTree::new(
+            LOG4_TREE_SIZE,
+            hash_account(StateModelVersion::V2, &Default::default()),
+        );
+        for (index, acc) in accounts.iter() {
+            tree.set(*index as u64, hash_account(StateModelVersion::V2, acc));
+        }
+        assert_eq!(root.state_hash, tree.root());
+
+        // The same accounts, written on a fresh state of version 2
+        let mut expected = genesis_db(StateModelVersion::V2);
+        for (index, acc) in accounts.iter() {
+            set_account(&mut expected, StateModelVersion::V2, *index, acc.clone()).unwrap();
+        }
+        assert_eq!(root.state_hash, self::root(&expected).state_hash);
+        assert_eq!(root.state_size, self::root(&expected).state_size);
+    }
+
+    #[test]
+    fn test_unknown_version() {
+        assert!(StateModelVersion::from_number(0).is_none());
+        assert!(matches!(
+            migrate(&genesis_db(StateModelVersion::V2), StateModelVersion::V2),
+            Err(MigrationError::NoMigration(2))
+        ));
+    }
+}
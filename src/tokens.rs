@@ -0,0 +1,29 @@
+use crate::config::LOG4_TOKENS_TREE_SIZE;
+use crate::core;
+use crate::tree::SparseTree;
+use bazuka::core::ZkHasher;
+use bazuka::zk::ZkHasher as _;
+use bazuka::zk::ZkScalar;
+use zeekit::merkle;
+
+pub fn hash_token(token: &core::Token) -> ZkScalar {
+    ZkHasher::hash(&[token.token_id, ZkScalar::from(token.amount)])
+}
+
+// The token subtree of an account is small enough to be rebuilt whenever the
+// account is read from the state.
+pub fn tokens_tree(acc: &core::Account) -> SparseTree {
+    let mut tree = SparseTree::new(LOG4_TOKENS_TREE_SIZE, hash_token(&core::Token::default()));
+    for (i, token) in acc.tokens.iter().enumerate() {
+        tree.set(i as u64, hash_token(token));
+    }
+    tree
+}
+
+pub fn tokens_root(acc: &core::Account) -> ZkScalar {
+    tokens_tree(acc).root()
+}
+
+pub fn prove_token(acc: &core::Account, token_index: u32) -> merkle::Proof<LOG4_TOKENS_TREE_SIZE> {
+    merkle::Proof::<LOG4_TOKENS_TREE_SIZE>(tokens_tree(acc).prove(token_index as u64))
+}
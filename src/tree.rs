@@ -0,0 +1,102 @@
+use bazuka::core::ZkHasher;
+use bazuka::zk::ZkHasher as _;
+use bazuka::zk::ZkScalar;
+use std::collections::HashMap;
+
+// Places `val` among the 3 proof values of a level, according to the table
+// explained in the README (Arity-4 merkle-proof placement).
+fn place(pos: u64, val: ZkScalar, proof: &[ZkScalar; 3]) -> [ZkScalar; 4] {
+    let mut vals = [ZkScalar::from(0); 4];
+    let mut it = proof.iter();
+    for (i, v) in vals.iter_mut().enumerate() {
+        *v = if i as u64 == pos {
+            val
+        } else {
+            *it.next().unwrap()
+        };
+    }
+    vals
+}
+
+// Recalculates the root of an arity-4 Poseidon tree, the same way
+// `calc_root_poseidon4` does it in the circuits.
+pub fn calc_root(mut index: u64, mut val: ZkScalar, proof: &[[ZkScalar; 3]]) -> ZkScalar {
+    for level in proof {
+        val = ZkHasher::hash(&place(index % 4, val, level));
+        index /= 4;
+    }
+    val
+}
+
+// In-memory sparse arity-4 Poseidon tree. Only non-default nodes are stored.
+#[derive(Debug, Clone)]
+pub struct SparseTree {
+    log4_size: u8,
+    defaults: Vec<ZkScalar>,
+    nodes: HashMap<(u8, u64), ZkScalar>,
+}
+
+impl SparseTree {
+    pub fn new(log4_size: u8, default_leaf: ZkScalar) -> Self {
+        let mut defaults = vec![default_leaf];
+        for _ in 0..log4_size {
+            let d = *defaults.last().unwrap();
+            defaults.push(ZkHasher::hash(&[d, d, d, d]));
+        }
+        Self {
+            log4_size,
+            defaults,
+            nodes: HashMap::new(),
+        }
+    }
+    pub fn default_leaf(&self) -> ZkScalar {
+        self.defaults[0]
+    }
+    pub fn capacity(&self) -> u64 {
+        1 << (2 * self.log4_size as u64)
+    }
+    fn node(&self, level: u8, index: u64) -> ZkScalar {
+        self.nodes
+            .get(&(level, index))
+            .cloned()
+            .unwrap_or(self.defaults[level as usize])
+    }
+    pub fn get(&self, index: u64) -> ZkScalar {
+        self.node(0, index)
+    }
+    pub fn root(&self) -> ZkScalar {
+        self.node(self.log4_size, 0)
+    }
+    pub fn set(&mut self, mut index: u64, mut val: ZkScalar) {
+        for level in 0..self.log4_size {
+            if val == self.defaults[level as usize] {
+                self.nodes.remove(&(level, index));
+            } else {
+                self.nodes.insert((level, index), val);
+            }
+            let first = index - index % 4;
+            let mut vals = [ZkScalar::from(0); 4];
+            for (i, v) in vals.iter_mut().enumerate() {
+                *v = self.node(level, first + i as u64);
+            }
+            val = ZkHasher::hash(&vals);
+            index /= 4;
+        }
+        self.nodes.insert((self.log4_size, 0), val);
+    }
+    pub fn prove(&self, mut index: u64) -> Vec<[ZkScalar; 3]> {
+        let mut proof = Vec::new();
+        for level in 0..self.log4_size {
+            let first = index - index % 4;
+            let mut siblings = Vec::new();
+            for i in first..first + 4 {
+                if i != index {
+                    siblings.push(self.node(level, i));
+                }
+            }
+            proof.push(siblings.try_into().unwrap());
+            index /= 4;
+        }
+        proof
+    }
+}
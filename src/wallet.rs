@@ -0,0 +1,97 @@
+use crate::hd::ExtendedKey;
+use bazuka::crypto::jubjub;
+use bazuka::zk::ZeroTransaction;
+use bip39::Mnemonic;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum WalletError {
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("json error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("invalid mnemonic: {0}")]
+    InvalidMnemonic(#[from] bip39::Error),
+}
+
+// MPN keys derived from a BIP39 mnemonic, one per derivation path
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MpnWallet {
+    mnemonic: String,
+}
+
+impl MpnWallet {
+    pub fn generate() -> Self {
+        let mut entropy = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut entropy);
+        Self {
+            mnemonic: Mnemonic::from_entropy(&entropy).unwrap().to_string(),
+        }
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Result<Self, WalletError> {
+        Ok(Self {
+            mnemonic: Mnemonic::parse(mnemonic)?.to_string(),
+        })
+    }
+
+    pub fn mnemonic(&self) -> &str {
+        &self.mnemonic
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, WalletError> {
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+
+    // Never overwrites an existing wallet. The mnemonic is stored unencrypted,
+    // so the file is only readable by its owner.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), WalletError> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let f = options.open(path)?;
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(())
+    }
+
+    pub fn keys(
+        &self,
+        path: &[u32],
+    ) -> Result<(jubjub::PublicKey, jubjub::PrivateKey), WalletError> {
+        let seed = Mnemonic::parse(&self.mnemonic)?.to_seed("");
+        Ok(ExtendedKey::master(&seed).derive(path).keys())
+    }
+
+    pub fn pub_key(&self, path: &[u32]) -> Result<jubjub::PublicKey, WalletError> {
+        Ok(self.keys(path)?.0)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn transfer(
+        &self,
+        path: &[u32],
+        nonce: u64,
+        src_index: u32,
+        dst_index: u32,
+        dst_pub_key: jubjub::PublicKey,
+        amount: u64,
+        fee: u64,
+    ) -> Result<ZeroTransaction, WalletError> {
+        let mut tx = ZeroTransaction {
+            nonce,
+            src_index,
+            dst_index,
+            dst_pub_key,
+            amount,
+            fee,
+            sig: jubjub::Signature::default(),
+        };
+        tx.sign(self.keys(path)?.1);
+        Ok(tx)
+    }
+}
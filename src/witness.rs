@@ -0,0 +1,454 @@
+use crate::circuits;
+use crate::config::{BATCH_SIZE, TOKENS_PER_ACCOUNT};
+use crate::core;
+use bazuka::crypto::jubjub;
+use bazuka::zk::{DepositWithdraw, ZeroTransaction, ZkScalar};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::Path;
+use thiserror::Error;
+use zeekit::merkle;
+
+// Bump when the layout of the witness files changes
+pub const WITNESS_VERSION: u32 = 2;
+
+#[derive(Error, Debug)]
+pub enum WitnessError {
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("json error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("unsupported witness version {0}")]
+    UnsupportedVersion(u32),
+    #[error("batch has {0} transitions, more than the batch size")]
+    BatchTooLarge(usize),
+    #[error("merkle proof has {0} levels, expected {1}")]
+    InvalidProof(usize, usize),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountWitness {
+    pub nonce: u64,
+    pub address: (ZkScalar, ZkScalar),
+    pub balance: u64,
+    pub tokens: [(ZkScalar, u64); TOKENS_PER_ACCOUNT], // Token-id and amount
+}
+
+impl From<&core::Account> for AccountWitness {
+    fn from(acc: &core::Account) -> Self {
+        Self {
+            nonce: acc.nonce,
+            address: (acc.address.0, acc.address.1),
+            balance: acc.balance,
+            tokens: acc.tokens.clone().map(|t| (t.token_id, t.amount)),
+        }
+    }
+}
+
+impl From<AccountWitness> for core::Account {
+    fn from(acc: AccountWitness) -> Self {
+        Self {
+            nonce: acc.nonce,
+            address: jubjub::PointAffine(acc.address.0, acc.address.1),
+            balance: acc.balance,
+            tokens: acc
+                .tokens
+                .map(|(token_id, amount)| core::Token { token_id, amount }),
+        }
+    }
+}
+
+fn proof_from_witness<const LOG4_SIZE: u8>(
+    proof: Vec<[ZkScalar; 3]>,
+) -> Result<merkle::Proof<LOG4_SIZE>, WitnessError> {
+    if proof.len() != LOG4_SIZE as usize {
+        return Err(WitnessError::InvalidProof(proof.len(), LOG4_SIZE as usize));
+    }
+    Ok(merkle::Proof::<LOG4_SIZE>(proof))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransitionWitness {
+    pub enabled: bool,
+    pub tx: ZeroTransaction,
+    pub src_before: AccountWitness,
+    pub src_proof: Vec<[ZkScalar; 3]>,
+    pub dst_before: AccountWitness,
+    pub dst_proof: Vec<[ZkScalar; 3]>,
+}
+
+impl From<&circuits::Transition> for TransitionWitness {
+    fn from(trans: &circuits::Transition) -> Self {
+        Self {
+            enabled: trans.enabled,
+            tx: trans.tx.clone(),
+            src_before: (&trans.src_before).into(),
+            src_proof: trans.src_proof.0.clone(),
+            dst_before: (&trans.dst_before).into(),
+            dst_proof: trans.dst_proof.0.clone(),
+        }
+    }
+}
+
+impl TransitionWitness {
+    pub fn into_transition(self) -> Result<circuits::Transition, WitnessError> {
+        Ok(circuits::Transition {
+            enabled: self.enabled,
+            tx: self.tx,
+            src_before: self.src_before.into(),
+            src_proof: proof_from_witness(self.src_proof)?,
+            dst_before: self.dst_before.into(),
+            dst_proof: proof_from_witness(self.dst_proof)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DepositWithdrawWitness {
+    pub index: u32,
+    pub pub_key: jubjub::PublicKey,
+    pub amount: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DepositWithdrawTransitionWitness {
+    pub enabled: bool,
+    pub tx: DepositWithdrawWitness,
+    pub before: AccountWitness,
+    pub proof: Vec<[ZkScalar; 3]>,
+}
+
+impl From<&circuits::DepositWithdrawTransition> for DepositWithdrawTransitionWitness {
+    fn from(trans: &circuits::DepositWithdrawTransition) -> Self {
+        Self {
+            enabled: trans.enabled,
+            tx: DepositWithdrawWitness {
+                index: trans.tx.index,
+                pub_key: trans.tx.pub_key.clone(),
+                amount: trans.tx.amount,
+            },
+            before: (&trans.before).into(),
+            proof: trans.proof.0.clone(),
+        }
+    }
+}
+
+impl DepositWithdrawTransitionWitness {
+    pub fn into_transition(self) -> Result<circuits::DepositWithdrawTransition, WitnessError> {
+        Ok(circuits::DepositWithdrawTransition {
+            enabled: self.enabled,
+            tx: DepositWithdraw {
+                index: self.tx.index,
+                pub_key: self.tx.pub_key,
+                amount: self.tx.amount,
+            },
+            before: self.before.into(),
+            proof: proof_from_witness(self.proof)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenTransferWitness {
+    pub nonce: u64,
+    pub src_index: u32,
+    pub src_token_index: u32,
+    pub dst_index: u32,
+    pub dst_token_index: u32,
+    pub dst_pub_key: jubjub::PublicKey,
+    pub token_id: ZkScalar,
+    pub amount: u64,
+    pub fee: u64,
+    pub sig: jubjub::Signature,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenDepositWithdrawWitness {
+    pub index: u32,
+    pub token_index: u32,
+    pub pub_key: jubjub::PublicKey,
+    pub token_id: ZkScalar,
+    pub amount: i64,
+}
+
+// A slot of the unified MPN circuit, with the token-subtree proofs next to the
+// account proofs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum MpnTransitionWitness {
+    Transfer {
+        enabled: bool,
+        tx: TokenTransferWitness,
+        src_before: AccountWitness,
+        src_proof: Vec<[ZkScalar; 3]>,
+        src_token_proof: Vec<[ZkScalar; 3]>,
+        dst_before: AccountWitness,
+        dst_proof: Vec<[ZkScalar; 3]>,
+        dst_token_proof: Vec<[ZkScalar; 3]>,
+    },
+    DepositWithdraw {
+        enabled: bool,
+        tx: TokenDepositWithdrawWitness,
+        before: AccountWitness,
+        proof: Vec<[ZkScalar; 3]>,
+        token_proof: Vec<[ZkScalar; 3]>,
+    },
+}
+
+impl From<&circuits::MpnTransition> for MpnTransitionWitness {
+    fn from(trans: &circuits::MpnTransition) -> Self {
+        match trans {
+            circuits::MpnTransition::Transfer(trans) => MpnTransitionWitness::Transfer {
+                enabled: trans.enabled,
+                tx: TokenTransferWitness {
+                    nonce: trans.tx.nonce,
+                    src_index: trans.tx.src_index,
+                    src_token_index: trans.tx.src_token_index,
+                    dst_index: trans.tx.dst_index,
+                    dst_token_index: trans.tx.dst_token_index,
+                    dst_pub_key: trans.tx.dst_pub_key.clone(),
+                    token_id: trans.tx.token_id,
+                    amount: trans.tx.amount,
+                    fee: trans.tx.fee,
+                    sig: trans.tx.sig.clone(),
+                },
+                src_before: (&trans.src_before).into(),
+                src_proof: trans.src_proof.0.clone(),
+                src_token_proof: trans.src_token_proof.0.clone(),
+                dst_before: (&trans.dst_before).into(),
+                dst_proof: trans.dst_proof.0.clone(),
+                dst_token_proof: trans.dst_token_proof.0.clone(),
+            },
+            circuits::MpnTransition::DepositWithdraw(trans) => {
+                MpnTransitionWitness::DepositWithdraw {
+                    enabled: trans.enabled,
+                    tx: TokenDepositWithdrawWitness {
+                        index: trans.tx.index,
+                        token_index: trans.tx.token_index,
+                        pub_key: trans.tx.pub_key.clone(),
+                        token_id: trans.tx.token_id,
+                        amount: trans.tx.amount,
+                    },
+                    before: (&trans.before).into(),
+                    proof: trans.proof.0.clone(),
+                    token_proof: trans.token_proof.0.clone(),
+                }
+            }
+        }
+    }
+}
+
+impl MpnTransitionWitness {
+    pub fn into_transition(self) -> Result<circuits::MpnTransition, WitnessError> {
+        Ok(match self {
+            MpnTransitionWitness::Transfer {
+                enabled,
+                tx,
+                src_before,
+                src_proof,
+                src_token_proof,
+                dst_before,
+                dst_proof,
+                dst_token_proof,
+            } => circuits::MpnTransition::Transfer(circuits::TokenTransition {
+                enabled,
+                tx: core::TokenTransfer {
+                    nonce: tx.nonce,
+                    src_index: tx.src_index,
+                    src_token_index: tx.src_token_index,
+                    dst_index: tx.dst_index,
+                    dst_token_index: tx.dst_token_index,
+                    dst_pub_key: tx.dst_pub_key,
+                    token_id: tx.token_id,
+                    amount: tx.amount,
+                    fee: tx.fee,
+                    sig: tx.sig,
+                },
+                src_before: src_before.into(),
+                src_proof: proof_from_witness(src_proof)?,
+                src_token_proof: proof_from_witness(src_token_proof)?,
+                dst_before: dst_before.into(),
+                dst_proof: proof_from_witness(dst_proof)?,
+                dst_token_proof: proof_from_witness(dst_token_proof)?,
+            }),
+            MpnTransitionWitness::DepositWithdraw {
+                enabled,
+                tx,
+                before,
+                proof,
+                token_proof,
+            } => {
+                circuits::MpnTransition::DepositWithdraw(circuits::TokenDepositWithdrawTransition {
+                    enabled,
+                    tx: core::TokenDepositWithdraw {
+                        index: tx.index,
+                        token_index: tx.token_index,
+                        pub_key: tx.pub_key,
+                        token_id: tx.token_id,
+                        amount: tx.amount,
+                    },
+                    before: before.into(),
+                    proof: proof_from_witness(proof)?,
+                    token_proof: proof_from_witness(token_proof)?,
+                })
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicInputs {
+    pub state: ZkScalar,
+    pub aux_data: ZkScalar,
+    pub next_state: ZkScalar,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "circuit", content = "transitions")]
+pub enum TransitionsWitness {
+    Update(Vec<TransitionWitness>),
+    DepositWithdraw(Vec<DepositWithdrawTransitionWitness>),
+    Mpn(Vec<MpnTransitionWitness>),
+}
+
+// Everything needed for proving a batch, independent of the proving process.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Witness {
+    pub version: u32,
+    pub inputs: PublicInputs,
+    pub transitions: TransitionsWitness,
+}
+
+pub enum WitnessCircuit {
+    Update(circuits::UpdateCircuit),
+    DepositWithdraw(circuits::DepositWithdrawCircuit),
+    Mpn(circuits::MpnCircuit),
+}
+
+impl Witness {
+    pub fn update(circuit: &circuits::UpdateCircuit) -> Self {
+        Self {
+            version: WITNESS_VERSION,
+            inputs: PublicInputs {
+                state: circuit.state,
+                aux_data: circuit.aux_data,
+                next_state: circuit.next_state,
+            },
+            transitions: TransitionsWitness::Update(
+                circuit.transitions.0.iter().map(|t| t.into()).collect(),
+            ),
+        }
+    }
+    pub fn deposit_withdraw(circuit: &circuits::DepositWithdrawCircuit) -> Self {
+        Self {
+            version: WITNESS_VERSION,
+            inputs: PublicInputs {
+                state: circuit.state,
+                aux_data: circuit.aux_data,
+                next_state: circuit.next_state,
+            },
+            transitions: TransitionsWitness::DepositWithdraw(
+                circuit.transitions.0.iter().map(|t| t.into()).collect(),
+            ),
+        }
+    }
+    pub fn mpn(circuit: &circuits::MpnCircuit) -> Self {
+        Self {
+            version: WITNESS_VERSION,
+            inputs: PublicInputs {
+                state: circuit.state,
+                aux_data: circuit.aux_data,
+                next_state: circuit.next_state,
+            },
+            transitions: TransitionsWitness::Mpn(
+                circuit.transitions.0.iter().map(|t| t.into()).collect(),
+            ),
+        }
+    }
+    pub fn circuit_name(&self) -> &'static str {
+        match self.transitions {
+            TransitionsWitness::Update(_) => "update",
+            TransitionsWitness::DepositWithdraw(_) => "deposit_withdraw",
+            TransitionsWitness::Mpn(_) => "mpn",
+        }
+    }
+    // Witnesses of other versions are rejected, wherever they come from
+    pub fn into_circuit(self) -> Result<WitnessCircuit, WitnessError> {
+        if self.version != WITNESS_VERSION {
+            return Err(WitnessError::UnsupportedVersion(self.version));
+        }
+        let inputs = self.inputs;
+        Ok(match self.transitions {
+            TransitionsWitness::Update(ts) => {
+                if ts.len() > BATCH_SIZE {
+                    return Err(WitnessError::BatchTooLarge(ts.len()));
+                }
+                WitnessCircuit::Update(circuits::UpdateCircuit {
+                    filled: true,
+                    state: inputs.state,
+                    aux_data: inputs.aux_data,
+                    next_state: inputs.next_state,
+                    transitions: Box::new(circuits::TransitionBatch::new(
+                        ts.into_iter()
+                            .map(|t| t.into_transition())
+                            .collect::<Result<Vec<_>, _>>()?,
+                    )),
+                })
+            }
+            TransitionsWitness::DepositWithdraw(ts) => {
+                if ts.len() > BATCH_SIZE {
+                    return Err(WitnessError::BatchTooLarge(ts.len()));
+                }
+                WitnessCircuit::DepositWithdraw(circuits::DepositWithdrawCircuit {
+                    filled: true,
+                    state: inputs.state,
+                    aux_data: inputs.aux_data,
+                    next_state: inputs.next_state,
+                    transitions: Box::new(circuits::DepositWithdrawTransitionBatch::new(
+                        ts.into_iter()
+                            .map(|t| t.into_transition())
+                            .collect::<Result<Vec<_>, _>>()?,
+                    )),
+                })
+            }
+            TransitionsWitness::Mpn(ts) => {
+                if ts.len() > BATCH_SIZE {
+                    return Err(WitnessError::BatchTooLarge(ts.len()));
+                }
+                WitnessCircuit::Mpn(circuits::MpnCircuit {
+                    filled: true,
+                    state: inputs.state,
+                    aux_data: inputs.aux_data,
+                    next_state: inputs.next_state,
+                    transitions: Box::new(circuits::MpnTransitionBatch::new(
+                        ts.into_iter()
+                            .map(|t| t.into_transition())
+                            .collect::<Result<Vec<_>, _>>()?,
+                    )),
+                })
+            }
+        })
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), WitnessError> {
+        serde_json::to_writer_pretty(File::create(path)?, self)?;
+        Ok(())
+    }
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, WitnessError> {
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut witness = Witness::update(&circuits::UpdateCircuit::default());
+        witness.version = WITNESS_VERSION + 1;
+        assert!(matches!(
+            witness.into_circuit(),
+            Err(WitnessError::UnsupportedVersion(v)) if v == WITNESS_VERSION + 1
+        ));
+    }
+}